@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+use std::thread;
+use tempfile::TempDir;
+
+const WRITES_PER_THREAD: usize = 100;
+
+// Every write is fsynced before it returns, so throughput with more writers
+// comes from group commit sharing appends and fsyncs between them.
+fn group_commit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_every_write_sync");
+    group.sample_size(10);
+
+    for threads in [1, 4, 16].iter() {
+        group.throughput(Throughput::Elements((threads * WRITES_PER_THREAD) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), threads, |b, &threads| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::default().sync_policy(SyncPolicy::EveryWrite);
+            let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+
+            b.iter(|| {
+                let handles: Vec<_> = (0..threads).map(|t| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        for i in 0..WRITES_PER_THREAD {
+                            store.set(format!("key{}-{}", t, i), "value".to_owned()).unwrap();
+                        }
+                    })
+                }).collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, group_commit);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+use crate::Sequencer;
+use std::time::Duration;
+
+/// Sets and removes applied together by `KvStore::write`.
+///
+/// The whole batch is appended to the log as one unit: after a crash either all of its
+/// operations are replayed or none of them is.
+///
+/// ```no_run
+/// # use kvs::{KvStore, WriteBatch};
+/// # let store = KvStore::open("db")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("user:123", "ann").set("name:ann", "user:123").remove("name:anne");
+/// store.write(batch)?;
+/// # Ok::<(), kvs::KvError>(())
+/// ```
+#[derive(Default, Debug)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<WriteOp>,
+    // keys that must still be at these versions, `None` for absent, or the batch
+    // fails with `KvError::ConditionFailed`
+    pub(crate) conditions: Vec<(Vec<u8>, Option<Sequencer>)>,
+}
+
+/// An operation of a `WriteBatch`, as engines other than `KvStore` get to apply it.
+#[derive(Debug)]
+pub enum WriteOp {
+    Set {key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>},
+    Remove {key: Vec<u8>},
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets `key` to `value` when the batch is written.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(WriteOp::Set {key: key.into(), value: value.into(), ttl: None});
+        self
+    }
+
+    /// Sets `key` to `value` when the batch is written, to expire `ttl` after that.
+    pub fn set_with_ttl(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, ttl: Duration) -> &mut WriteBatch {
+        self.ops.push(WriteOp::Set {key: key.into(), value: value.into(), ttl: Some(ttl)});
+        self
+    }
+
+    /// Removes `key` when the batch is written. The batch fails as a whole with
+    /// `KvError::KeyNotFound` if the key is not set by then.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(WriteOp::Remove {key: key.into()});
+        self
+    }
+
+    /// The operations in the order they were added.
+    pub fn ops(&self) -> &[WriteOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
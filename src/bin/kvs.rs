@@ -1,12 +1,78 @@
 use std::env;
-use clap::{App, SubCommand, Arg};
-use kvs::{Result, KvError, KvStore};
+use std::fs;
+use std::path::Path;
+use clap::{App, AppSettings, SubCommand, Arg, ArgMatches};
+use kvs::{Result, CasOutcome, KvError, KvStore, KvStoreOptions, Scan, SyncPolicy};
+use std::time::Duration;
 use std::process::exit;
 
+const ENGINE_FILE: &str = "engine";
+const DEFAULT_ENGINE: &str = "kvs";
 
 fn main() -> Result<()> {
+    env_logger::init();
+
     let kvs_app = App::new("kvs")
         .version(env!("CARGO_PKG_VERSION"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Sets the storage engine")
+                .possible_values(&["kvs"])
+                .takes_value(true)
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("max-file-size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .help("Size after which a new log file is started")
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("compaction-files")
+                .long("compaction-files")
+                .value_name("COUNT")
+                .help("Compact once there are more log files than this")
+                .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("compaction-stale-ratio")
+                .long("compaction-stale-ratio")
+                .value_name("RATIO")
+                .help("Compact once this fraction of the logged bytes is stale")
+                .validator(|v| v.parse::<f64>().map(|_| ()).map_err(|e| e.to_string()))
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("checkpoint-bytes")
+                .long("checkpoint-bytes")
+                .value_name("BYTES")
+                .help("Checkpoint the index after this many appended bytes, 0 to disable")
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .value_name("POLICY")
+                .help("When to fsync: never, every-write, <N>ms or <N>bytes")
+                .validator(|v| parse_sync_policy(&v).map(|_| ()).ok_or_else(|| format!("invalid sync policy {}", v)))
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("NAME")
+                .help("Name of the subdirectory holding the log files")
+                .global(true)
+        )
+        .arg(Arg::with_name("read-only").long("read-only").help("Never modify the store").global(true))
+        .arg(Arg::with_name("no-create").long("no-create").help("Fail if there is no store yet").global(true))
         .subcommand(
             SubCommand::with_name("get")
                 .arg(Arg::with_name("<KEY>").help("ENTER A KEY").required(true))
@@ -15,21 +81,164 @@ fn main() -> Result<()> {
             SubCommand::with_name("set")
                 .arg(Arg::with_name("<KEY>").help("ENTER A KEY").required(true))
                 .arg(Arg::with_name("<VALUE>").help("ENTER A VALUE").required(true))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("DURATION")
+                        .help("Expire the key after this long: <N>ms, <N>s, <N>m or <N>h")
+                        .validator(|v| parse_duration(&v).map(|_| ()).ok_or_else(|| format!("invalid duration {}", v)))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("Prints the time until a key expires")
+                .arg(Arg::with_name("<KEY>").help("ENTER A KEY").required(true))
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(Arg::with_name("<KEY>").help("ENTER A KEY").required(true))
         )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Sets a key only if it still has the expected value")
+                .arg(Arg::with_name("<KEY>").help("ENTER A KEY").required(true))
+                .arg(Arg::with_name("expected").long("expected").value_name("VALUE")
+                    .help("The value the key must have, it must not exist without this"))
+                .arg(Arg::with_name("new").long("new").value_name("VALUE")
+                    .help("The value to set, the key is removed without this"))
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("Lists the keys starting with a prefix, all keys without one")
+                .arg(Arg::with_name("<PREFIX>").help("ENTER A KEY PREFIX"))
+                .arg(limit_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Prints the keys starting with a prefix and their values")
+                .arg(Arg::with_name("<PREFIX>").help("ENTER A KEY PREFIX").required(true))
+                .arg(limit_arg())
+                .arg(Arg::with_name("keys-only").long("keys-only").help("Only print the keys"))
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Rewrites a store written by an older version in the current format")
+        )
         .get_matches();
 
+    let dir = env::current_dir()?;
+    let options = store_options(&kvs_app);
+    let engine = kvs_app.value_of("engine").unwrap_or(DEFAULT_ENGINE);
+    let may_create = !kvs_app.is_present("read-only") && !kvs_app.is_present("no-create");
+    let engine = match check_engine(&dir, engine, may_create) {
+        Ok(engine) => engine,
+        Err(e @ KvError::WrongEngine {..}) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+        Err(e) => return Err(e),
+    };
+
+    match engine.as_str() {
+        "kvs" if kvs_app.subcommand_name() == Some("upgrade") => {
+            let upgraded = KvStore::upgrade(&dir, options)?;
+            println!("Upgraded {} log files", upgraded);
+            Ok(())
+        },
+        "kvs" => match KvStore::open_with_options(&dir, options) {
+            Ok(store) => run(store, &kvs_app),
+            Err(e @ KvError::UnsupportedVersion {..}) | Err(e @ KvError::UpgradeRequired(_)) => {
+                eprintln!("{}", e);
+                exit(1);
+            },
+            Err(e) => Err(e),
+        },
+        _ => unreachable!()
+    }
+}
+
+// The engine used to create a directory is recorded on first use, so later runs can refuse
+// to open it with a different engine.
+fn check_engine(dir: &Path, requested: &str, may_create: bool) -> Result<String> {
+    let engine_path = dir.join(ENGINE_FILE);
+    if engine_path.exists() {
+        let current = fs::read_to_string(&engine_path)?.trim().to_owned();
+        if current != requested {
+            return Err(KvError::WrongEngine {current, requested: requested.to_owned()});
+        }
+    } else if may_create {
+        fs::write(&engine_path, requested)?;
+    }
 
+    Ok(requested.to_owned())
+}
 
+// the knobs are validated by clap already
+fn store_options(kvs_app: &ArgMatches) -> KvStoreOptions {
+    let mut options = KvStoreOptions::default()
+        .read_only(kvs_app.is_present("read-only"))
+        .create_if_missing(!kvs_app.is_present("no-create"));
+    if let Some(size) = kvs_app.value_of("max-file-size") {
+        options = options.max_file_size(size.parse().unwrap());
+    }
+    if let Some(files) = kvs_app.value_of("compaction-files") {
+        options = options.compaction_file_count(files.parse().unwrap());
+    }
+    if let Some(ratio) = kvs_app.value_of("compaction-stale-ratio") {
+        options = options.compaction_stale_ratio(ratio.parse().unwrap());
+    }
+    if let Some(bytes) = kvs_app.value_of("checkpoint-bytes") {
+        options = options.checkpoint_bytes(bytes.parse().unwrap());
+    }
+    if let Some(policy) = kvs_app.value_of("sync") {
+        options = options.sync_policy(parse_sync_policy(policy).unwrap());
+    }
+    if let Some(name) = kvs_app.value_of("data-dir") {
+        options = options.data_dir(name);
+    }
+    options
+}
+
+fn parse_sync_policy(policy: &str) -> Option<SyncPolicy> {
+    match policy {
+        "never" => Some(SyncPolicy::Never),
+        "every-write" => Some(SyncPolicy::EveryWrite),
+        _ if policy.ends_with("bytes") => {
+            policy.trim_end_matches("bytes").parse().ok().map(SyncPolicy::Bytes)
+        },
+        _ if policy.ends_with("ms") => {
+            policy.trim_end_matches("ms").parse().ok().map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+        },
+        _ => None
+    }
+}
+
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration.find(|c: char| !c.is_ascii_digit())?;
+    let count: u64 = duration[..split].parse().ok()?;
+    match &duration[split..] {
+        "ms" => Some(Duration::from_millis(count)),
+        "s" => Some(Duration::from_secs(count)),
+        "m" => count.checked_mul(60).map(Duration::from_secs),
+        "h" => count.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+fn limit_arg() -> Arg<'static, 'static> {
+    Arg::with_name("limit")
+        .long("limit")
+        .value_name("COUNT")
+        .help("Stops after this many keys")
+        .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+}
+
+fn run(kv: KvStore, kvs_app: &ArgMatches) -> Result<()> {
     match kvs_app.subcommand() {
         ("get", Some(matches)) =>  {
             let k = matches.value_of("<KEY>").expect("<KEY> argument is missing");
-            let mut kv = KvStore::open(env::current_dir()?)?;
 
-            if let Some(v) = kv.get(k.to_owned())? {
+            if let Some(v) = kv.get(k)? {
                 println!("{}", v);
             } else {
                 println!("Key not found");
@@ -37,8 +246,7 @@ fn main() -> Result<()> {
         },
         ("rm", Some(matches)) => {
             let k = matches.value_of("<KEY>").expect("<KEY> argument is missing");
-            let mut kv = KvStore::open(env::current_dir()?)?;
-            match kv.remove(k.to_string()) {
+            match kv.remove(k) {
                 Ok(()) => (),
                 Err(KvError::KeyNotFound) => {
                     println!("Key not found");
@@ -51,11 +259,68 @@ fn main() -> Result<()> {
             let k = matches.value_of("<KEY>").expect("<KEY> argument is missing");
             let v = matches.value_of("<VALUE>").expect("<VALUE> argument is missing");
 
-            let mut kv = KvStore::open(env::current_dir()?)?;
-            kv.set(k.to_owned(), v.to_owned())?;
+            match matches.value_of("ttl") {
+                Some(ttl) => kv.set_with_ttl(k, v, parse_duration(ttl).unwrap())?,
+                None => kv.set(k.to_owned(), v.to_owned())?,
+            }
+        }
+        ("ttl", Some(matches)) => {
+            let k = matches.value_of("<KEY>").expect("<KEY> argument is missing");
+            match kv.ttl(k) {
+                // whole seconds, rounded up so a key about to expire does not show as 0s
+                Ok(Some(ttl)) => println!("{}s", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("No expiry"),
+                Err(KvError::KeyNotFound) => {
+                    println!("Key not found");
+                    exit(1);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        ("cas", Some(matches)) => {
+            let k = matches.value_of("<KEY>").expect("<KEY> argument is missing");
+            let expected = matches.value_of("expected").map(str::to_owned);
+            let new = matches.value_of("new").map(str::to_owned);
+
+            if let CasOutcome::Mismatch(current) = kv.compare_and_swap(k, expected, new)? {
+                match current {
+                    Some(v) => println!("{}", v),
+                    None => println!("Key not found"),
+                }
+                exit(1);
+            }
+        }
+        ("ls", Some(matches)) => {
+            let prefix = matches.value_of("<PREFIX>").unwrap_or("");
+            print_keys(kv.keys_with_prefix(prefix), matches)?;
+        }
+        ("scan", Some(matches)) => {
+            let prefix = matches.value_of("<PREFIX>").expect("<PREFIX> argument is missing");
+            if matches.is_present("keys-only") {
+                print_keys(kv.keys_with_prefix(prefix), matches)?;
+            } else {
+                for entry in with_limit(kv.scan_prefix(prefix), matches) {
+                    let (k, v) = entry?;
+                    println!("{}\t{}", k, v);
+                }
+            }
         }
         _ => unreachable!()
     }
 
     Ok(())
 }
+
+fn with_limit<T>(scan: Scan<T>, matches: &ArgMatches) -> Scan<T> {
+    match matches.value_of("limit") {
+        Some(limit) => scan.limit(limit.parse().unwrap()),
+        None => scan,
+    }
+}
+
+fn print_keys(keys: Scan<String>, matches: &ArgMatches) -> Result<()> {
+    for key in with_limit(keys, matches) {
+        println!("{}", key?);
+    }
+    Ok(())
+}
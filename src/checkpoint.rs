@@ -0,0 +1,172 @@
+use crate::{FileId, FileStats, Index, Result};
+use crate::index::Version;
+use crate::record;
+use crate::record::Next;
+use crate::storage::sync_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+// A copy of the index as of a position in the log, so opening a store only replays what was
+// written after that position.
+//
+//   | header | entry | entry | ... | version | version | ... | evicted | evicted | ...
+//
+// where the versions are those the retention keeps besides the entries, and the evicted
+// entries those that expired and whose records compaction still has to deal with.
+// Each part is framed like a log record, the header as json and the rest as binary index
+// entries, see `record`. The header also lists the length of every file
+// before the position; if compaction has replaced any of them since, the checkpoint points
+// into files that are gone and is ignored.
+const CHECKPOINT_NAME: &str = "INDEX";
+
+pub(crate) struct Checkpoint {
+    // the log up to here is covered
+    pub(crate) f_id: FileId,
+    pub(crate) offset: u64,
+    pub(crate) files: Vec<(FileId, u64)>,
+    pub(crate) index: Index,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointHeader {
+    f_id: FileId,
+    offset: u64,
+    files: Vec<(FileId, u64)>,
+    // json maps only take string keys
+    file_stats: Vec<(FileId, FileStats)>,
+    entries: u64,
+    #[serde(default)]
+    versions: u64,
+    #[serde(default)]
+    evicted: u64,
+    #[serde(default)]
+    format: u32,
+}
+
+impl Checkpoint {
+    // returns the size of the checkpoint written
+    pub(crate) fn write(&self, storage_path: &Path) -> Result<u64> {
+        let header = CheckpointHeader {
+            f_id: self.f_id.clone(),
+            offset: self.offset,
+            files: self.files.clone(),
+            file_stats: self.index.file_stats().iter()
+                .map(|(f_id, stats)| (f_id.clone(), stats.clone()))
+                .collect(),
+            entries: self.index.entries().len() as u64,
+            versions: self.index.versions().count() as u64,
+            evicted: self.index.evicted().count() as u64,
+            format: record::ENTRY_FORMAT,
+        };
+
+        let tmp_path = storage_path.join(format!("{}.tmp", CHECKPOINT_NAME));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&record::encode_json(&header)?)?;
+        for (key, (lp, sequencer, expires_at)) in self.index.entries() {
+            writer.write_all(&record::encode_entry(key, lp, sequencer, *expires_at, false)?)?;
+        }
+        for (key, version) in self.index.versions() {
+            writer.write_all(&record::encode_entry(key, &version.lp, &version.sequencer,
+                                                   version.expires_at, version.tombstone)?)?;
+        }
+        for (key, (lp, sequencer, expires_at)) in self.index.evicted() {
+            writer.write_all(&record::encode_entry(key, lp, sequencer, *expires_at, false)?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        let len = writer.get_ref().metadata()?.len();
+
+        // same dance as the compaction manifest, a torn checkpoint is never visible
+        fs::rename(&tmp_path, storage_path.join(CHECKPOINT_NAME))?;
+        sync_dir(storage_path)?;
+        Ok(len)
+    }
+
+    // size of the saved checkpoint, `0` if there is none
+    pub(crate) fn len(storage_path: &Path) -> Result<u64> {
+        match fs::metadata(storage_path.join(CHECKPOINT_NAME)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn remove(storage_path: &Path) -> Result<()> {
+        match fs::remove_file(storage_path.join(CHECKPOINT_NAME)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    // the saved checkpoint, `None` if there is none or it is damaged
+    pub(crate) fn read(storage_path: &Path) -> Result<Option<Checkpoint>> {
+        let path = storage_path.join(CHECKPOINT_NAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let (header, mut pos) = match record::read_next_json::<_, CheckpointHeader>(&mut reader, len)? {
+            Next::Record(header, _) if header.format != record::ENTRY_FORMAT => {
+                log::info!("ignoring index checkpoint {:?} written by an older version", path);
+                return Ok(None);
+            },
+            Next::Record(header, header_len) => (header, header_len),
+            _ => return Ok(damaged(&path)),
+        };
+        let mut kv_index = BTreeMap::new();
+        for _ in 0..header.entries {
+            match record::read_next_entry(&mut reader, len - pos)? {
+                Next::Record((key, version), entry_len) if !version.tombstone => {
+                    kv_index.insert(key, (version.lp, version.sequencer, version.expires_at));
+                    pos += entry_len;
+                },
+                _ => return Ok(damaged(&path)),
+            }
+        }
+        let mut history: HashMap<Vec<u8>, Vec<Version>> = HashMap::new();
+        for _ in 0..header.versions {
+            match record::read_next_entry(&mut reader, len - pos)? {
+                Next::Record((key, version), entry_len) => {
+                    history.entry(key).or_default().push(version);
+                    pos += entry_len;
+                },
+                _ => return Ok(damaged(&path)),
+            }
+        }
+        let mut evicted = HashMap::new();
+        for _ in 0..header.evicted {
+            match record::read_next_entry(&mut reader, len - pos)? {
+                Next::Record((key, version), entry_len) if !version.tombstone => {
+                    evicted.insert(key, (version.lp, version.sequencer, version.expires_at));
+                    pos += entry_len;
+                },
+                _ => return Ok(damaged(&path)),
+            }
+        }
+        if pos != len {
+            return Ok(damaged(&path));
+        }
+
+        let log_end = (header.f_id.clone(), header.offset);
+        Ok(Some(Checkpoint {
+            f_id: header.f_id,
+            offset: header.offset,
+            files: header.files,
+            index: Index::from_parts(kv_index, history, evicted, header.file_stats.into_iter().collect(), log_end),
+        }))
+    }
+}
+
+fn damaged(path: &Path) -> Option<Checkpoint> {
+    log::warn!("ignoring damaged index checkpoint {:?}", path);
+    None
+}
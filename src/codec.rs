@@ -0,0 +1,40 @@
+use crate::{KvError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Turns typed values into the bytes stored for them and back, see `KvStore::set_typed`.
+pub trait Codec {
+    /// Encodes `value` into the bytes to store, failing with `KvError::Encode` if it cannot be.
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes stored bytes, failing with `KvError::Decode` if they do not hold a `T`.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Stores values as JSON, readable by anything that reads the store as strings.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| KvError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| KvError::Decode(e.to_string()))
+    }
+}
+
+/// Stores values in the compact binary encoding of `bincode`.
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| KvError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|e| KvError::Decode(e.to_string()))
+    }
+}
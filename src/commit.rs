@@ -0,0 +1,166 @@
+use crate::{KvError, Result};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+// Group commit for concurrent writers.
+//
+// Every writer queues its operation. Whoever finds no commit in progress becomes the
+// leader: it takes everything queued so far and applies it as one batch, which ends up as
+// a single append and a single flush/fsync. The other writers sleep until the leader has
+// published their result, so a write still only returns once it is durable.
+pub(crate) struct GroupCommit<Op> {
+    queue: Mutex<CommitQueue<Op>>,
+    committed: Condvar,
+}
+
+struct CommitQueue<Op> {
+    pending: Vec<(u64, Op)>,
+    results: HashMap<u64, Result<()>>,
+    next_ticket: u64,
+    leader_active: bool,
+}
+
+impl<Op> GroupCommit<Op> {
+    pub fn new() -> Self {
+        GroupCommit {
+            queue: Mutex::new(CommitQueue {
+                pending: Vec::new(),
+                results: HashMap::new(),
+                next_ticket: 0,
+                leader_active: false,
+            }),
+            committed: Condvar::new(),
+        }
+    }
+
+    // Commits `op` together with whatever other writers queued concurrently.
+    //
+    // `apply` gets the batch in queue order and returns one result per operation, or an
+    // error if the batch as a whole could not be written. Only one `apply` runs at a time.
+    pub fn commit<F>(&self, op: Op, apply: F) -> Result<()>
+        where F: FnOnce(Vec<Op>) -> Result<Vec<Result<()>>> {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, op));
+
+        let mut apply = Some(apply);
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+
+            if !queue.leader_active {
+                // our op is still pending, otherwise its result would have been there
+                queue.leader_active = true;
+                let (tickets, ops): (Vec<u64>, Vec<Op>) = queue.pending.drain(..).unzip();
+                drop(queue);
+
+                let guard = LeaderGuard {commit: self, leader: ticket, tickets: Some(tickets)};
+                let apply = apply.take().expect("a writer leads at most one batch");
+                let result = apply(ops);
+                let tickets = guard.disarm();
+                let results = match result {
+                    Ok(results) => results,
+                    Err(e) => {
+                        let msg = e.to_string();
+                        let mut results: Vec<Result<()>> = tickets.iter()
+                            .map(|_| Err(KvError::CommitFailed(msg.clone())))
+                            .collect();
+                        // the leader gets the original error
+                        let own = tickets.iter().position(|t| *t == ticket).unwrap();
+                        results[own] = Err(e);
+                        results
+                    }
+                };
+
+                queue = self.queue.lock().unwrap();
+                queue.results.extend(tickets.into_iter().zip(results));
+                queue.leader_active = false;
+                self.committed.notify_all();
+                continue;
+            }
+
+            queue = self.committed.wait(queue).unwrap();
+        }
+    }
+}
+
+// Fails the batch of a leader that panics in `apply`, so the writers waiting on it wake up
+// and a later writer can lead the next batch.
+struct LeaderGuard<'a, Op> {
+    commit: &'a GroupCommit<Op>,
+    // nobody waits for the result of the leader itself
+    leader: u64,
+    tickets: Option<Vec<u64>>,
+}
+
+impl<Op> LeaderGuard<'_, Op> {
+    // `apply` returned, the leader publishes the results itself
+    fn disarm(mut self) -> Vec<u64> {
+        self.tickets.take().unwrap()
+    }
+}
+
+impl<Op> Drop for LeaderGuard<'_, Op> {
+    fn drop(&mut self) {
+        if let Some(tickets) = self.tickets.take() {
+            // the queue lock is not held during `apply`, so it cannot be poisoned by this panic
+            let mut queue = self.commit.queue.lock().unwrap_or_else(|e| e.into_inner());
+            for ticket in tickets.into_iter().filter(|ticket| *ticket != self.leader) {
+                queue.results.insert(ticket, Err(KvError::CommitFailed("the writer applying the batch panicked".to_owned())));
+            }
+            queue.leader_active = false;
+            self.commit.committed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    // A writer that panics while applying a group commit batch fails the batch; the writers
+    // waiting on it return, and later writes still go through.
+    #[test]
+    fn survives_panicking_leader() {
+        let commit = Arc::new(GroupCommit::<u32>::new());
+        let (started, leading) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let first = {
+            let commit = commit.clone();
+            thread::spawn(move || commit.commit(0, |ops| {
+                started.send(()).unwrap();
+                released.recv().unwrap();
+                Ok(ops.iter().map(|_| Ok(())).collect())
+            }))
+        };
+        leading.recv().unwrap();
+
+        let writers: Vec<_> = (1..5).map(|op| {
+            let commit = commit.clone();
+            thread::spawn(move || commit.commit(op, |ops| {
+                assert_eq!(ops.len(), 1, "the leader of a batch of several writes panics");
+                Ok(ops.iter().map(|_| Ok(())).collect())
+            }))
+        }).collect();
+        // all of them end up in the next batch
+        while commit.queue.lock().unwrap().pending.len() < 4 {
+            thread::yield_now();
+        }
+        release.send(()).unwrap();
+        assert!(first.join().unwrap().is_ok());
+
+        let mut panicked = 0;
+        for writer in writers {
+            match writer.join() {
+                Ok(result) => assert!(matches!(result, Err(KvError::CommitFailed(_)))),
+                Err(_) => panicked += 1,
+            }
+        }
+        assert_eq!(panicked, 1);
+        assert!(commit.commit(5, |ops| Ok(ops.iter().map(|_| Ok(())).collect())).is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{Index, Storage};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// how often expired entries are looked for, and unsynced writes synced under
+// `SyncPolicy::Interval` if that is shorter, when nothing else wakes the worker up
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Runs compaction and index checkpoints on a background thread so the writer crossing
+// a threshold does not pay for rewriting the dataset or saving the index. In between it
+// sweeps expired entries out of the index, so their files get compacted even if nobody
+// writes anymore, and syncs writes the sync policy would otherwise leave unsynced.
+//
+// Dropping the compactor waits for a running compaction to finish, so the data directory
+// is never reopened while the old store is still rewriting it.
+pub(crate) struct Compactor {
+    sender: Mutex<Option<Sender<Task>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(PartialEq)]
+enum Task {
+    Compaction,
+    Checkpoint,
+}
+
+impl Compactor {
+    pub fn start(storage: Arc<Storage>, index: Arc<RwLock<Index>>) -> Compactor {
+        let (sender, receiver) = channel::<Task>();
+
+        let tick = storage.sync_interval().map_or(SWEEP_INTERVAL, |interval| interval.min(SWEEP_INTERVAL));
+        let handle = thread::spawn(move || {
+            loop {
+                let mut tasks = match receiver.recv_timeout(tick) {
+                    Ok(task) => vec![task],
+                    Err(RecvTimeoutError::Timeout) => Vec::new(),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                // coalesce requests piled up while the previous run was busy
+                tasks.extend(receiver.try_iter());
+
+                if let Err(e) = storage.sync_pending() {
+                    log::error!("syncing the log failed: {}", e);
+                }
+
+                match storage.sweep_expired(&index) {
+                    Ok(true) => tasks.push(Task::Compaction),
+                    Ok(false) => {},
+                    Err(e) => log::error!("sweeping expired entries failed: {}", e),
+                }
+                let mut checkpoint = tasks.contains(&Task::Checkpoint);
+                if tasks.contains(&Task::Compaction) && storage.should_compaction(&index.read().unwrap()) {
+                    match storage.compaction(&index) {
+                        // the files the last checkpoint points into may be gone now
+                        Ok(()) => checkpoint |= storage.checkpoints_enabled(),
+                        Err(e) => log::error!("compaction failed: {}", e),
+                    }
+                }
+                if checkpoint {
+                    if let Err(e) = storage.checkpoint(&index) {
+                        log::error!("index checkpoint failed: {}", e);
+                    }
+                }
+            }
+        });
+
+        Compactor {
+            sender: Mutex::new(Some(sender)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.send(Task::Compaction);
+    }
+
+    pub fn checkpoint(&self) {
+        self.send(Task::Checkpoint);
+    }
+
+    fn send(&self, task: Task) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            // the worker only goes away on drop
+            let _ = sender.send(task);
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crate::{CasOutcome, Result, WriteBatch};
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// A pluggable key/value storage engine.
+///
+/// `KvStore` is the log-structured implementation shipped with this crate; callers that only
+/// depend on this trait can swap in other engines without touching their call sites.
+/// Engines are cloneable handles that can be shared between threads.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Iterator over the entries of a key range, in key order, returned by `scan`.
+    type Scan: DoubleEndedIterator<Item = Result<(String, String)>>;
+
+    /// Sets the value of a string key, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the value of a string key, returning `None` if the key does not exist.
+    ///
+    /// The key is only borrowed, so a `&str` can be looked up without allocating.
+    fn get<K: AsRef<str>>(&self, key: K) -> Result<Option<String>>;
+
+    /// Removes a key, returning `KvError::KeyNotFound` if the key does not exist.
+    fn remove<K: Into<String>>(&self, key: K) -> Result<()>;
+
+    /// Sets `key` to `value` for `ttl`, after which the key reads as absent.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+    /// Time left until `key` expires, `None` if it never does. Fails with
+    /// `KvError::KeyNotFound` if the key is not set.
+    fn ttl<K: AsRef<str>>(&self, key: K) -> Result<Option<Duration>>;
+
+    /// Sets `key` to `new` if its value is `expected`, atomically with respect to other writers.
+    ///
+    /// `None` stands for the key being absent, as expected value it requires the key not to
+    /// exist and as new value it removes the key.
+    fn compare_and_swap<K: Into<String>>(&self, key: K, expected: Option<String>, new: Option<String>) -> Result<CasOutcome>;
+
+    /// Applies all operations of `batch` as one atomic unit, nothing is written if any fails.
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Iterates over the entries with keys in `range`, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Self::Scan;
+
+    /// Iterates over the entries with keys starting with `prefix`, in key order.
+    fn scan_prefix<K: AsRef<str>>(&self, prefix: K) -> Self::Scan;
+}
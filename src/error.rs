@@ -1,5 +1,10 @@
+// failure_derive predates the non_local_definitions lint
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
 use std::time::SystemTimeError;
 
 #[derive(Fail, Debug)]
@@ -13,6 +18,15 @@ pub enum KvError {
     #[fail(display = "{}", _0)]
     Time(std::time::SystemTimeError),
 
+    #[fail(display = "{}", _0)]
+    Utf8(FromUtf8Error),
+
+    #[fail(display = "Value could not be encoded: {}", _0)]
+    Encode(String),
+
+    #[fail(display = "Stored value does not decode as the requested type: {}", _0)]
+    Decode(String),
+
     #[fail(display = "Key not found")]
     KeyNotFound,
 
@@ -21,6 +35,27 @@ pub enum KvError {
 
     #[fail(display = "Conflicts detected when update")]
     ConflictError,
+
+    #[fail(display = "A key the write depends on was changed in the meantime")]
+    ConditionFailed,
+
+    #[fail(display = "Corrupted record in {:?} at offset {}", file, offset)]
+    Corruption { file: PathBuf, offset: u64 },
+
+    #[fail(display = "Write failed together with its group commit batch: {}", _0)]
+    CommitFailed(String),
+
+    #[fail(display = "Store was opened read-only")]
+    ReadOnly,
+
+    #[fail(display = "Directory was created by engine {}, refusing to open it with {}", current, requested)]
+    WrongEngine { current: String, requested: String },
+
+    #[fail(display = "{:?} uses on-disk format version {}, which is newer than this build supports", file, version)]
+    UnsupportedVersion { file: PathBuf, version: u32 },
+
+    #[fail(display = "{:?} uses an old on-disk format, run `kvs upgrade` first", _0)]
+    UpgradeRequired(PathBuf),
 }
 
 impl From<io::Error> for KvError {
@@ -41,4 +76,10 @@ impl From<std::time::SystemTimeError> for KvError {
     }
 }
 
+impl From<FromUtf8Error> for KvError {
+    fn from(error: FromUtf8Error) -> Self {
+        KvError::Utf8(error)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, KvError>;
\ No newline at end of file
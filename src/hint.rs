@@ -0,0 +1,97 @@
+use crate::{Command, LogPointer, Result};
+use crate::index::Version;
+use crate::record;
+use crate::record::Next;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+// A hint file sits next to a compacted log file and holds, for every record in it, what the
+// index needs without the value, so opening a store does not have to read values back.
+//
+//   | header: length of the log file | entry | entry | ...
+//
+// Each part is framed like a log record, the header as json and the entries as binary index
+// entries, see `record`. A hint that fails a checksum, or was written for a log file of a
+// different length, is ignored and the log file is replayed instead.
+pub(crate) const HINT_EXT: &str = "hint";
+
+#[derive(Serialize, Deserialize)]
+struct HintHeader {
+    data_len: u64,
+    #[serde(default)]
+    format: u32,
+}
+
+// a record of the log file as the index takes it
+#[derive(Debug)]
+pub(crate) struct HintEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) version: Version,
+}
+
+impl HintEntry {
+    pub(crate) fn new(cmd: &Command, lp: &LogPointer) -> HintEntry {
+        HintEntry {
+            key: cmd.get_key().to_vec(),
+            version: Version {
+                lp: lp.clone(),
+                sequencer: cmd.get_sequencer().clone(),
+                expires_at: cmd.get_expires_at(),
+                tombstone: matches!(cmd, Command::Rm {..}),
+            },
+        }
+    }
+}
+
+pub(crate) fn write(path: &Path, data_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let mut buf = record::encode_json(&HintHeader {data_len, format: record::ENTRY_FORMAT})?;
+    for HintEntry {key, version} in entries {
+        buf.extend_from_slice(&record::encode_entry(key, &version.lp, &version.sequencer,
+                                                    version.expires_at, version.tombstone)?);
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// entries of the hint at `path`, `None` if there is none usable for a log file of `data_len` bytes
+pub(crate) fn read(path: &Path, data_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut pos = match record::read_next_json::<_, HintHeader>(&mut reader, hint_len)? {
+        Next::Record(header, _) if header.format != record::ENTRY_FORMAT => {
+            log::info!("ignoring hint file {:?} written by an older version", path);
+            return Ok(None);
+        },
+        Next::Record(header, len) if header.data_len == data_len => len,
+        _ => {
+            log::warn!("ignoring hint file {:?} that does not match its log file", path);
+            return Ok(None);
+        }
+    };
+    let mut entries = Vec::new();
+    loop {
+        match record::read_next_entry(&mut reader, hint_len - pos)? {
+            Next::Record((key, version), len) => {
+                entries.push(HintEntry {key, version});
+                pos += len;
+            },
+            Next::Eof => return Ok(Some(entries)),
+            Next::Incomplete | Next::Corrupt => {
+                log::warn!("ignoring damaged hint file {:?}", path);
+                return Ok(None);
+            }
+        }
+    }
+}
@@ -1,54 +1,438 @@
-use crate::{LogPointer, Result, Command, Sequencer, KvError};
-use std::collections::BTreeMap;
-use std::collections::btree_map::{IterMut};
+use crate::{LogPointer, Result, Command, Sequencer, KvError, FileId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::btree_map::{Iter, IterMut, Range};
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+// where the live record of a key is, its version and its expiry in milliseconds since the epoch
+pub type Entry = (LogPointer, Sequencer, Option<u64>);
+
+#[derive(Clone, Debug, Default)]
 pub struct Index {
-    kv_index: BTreeMap<String, (LogPointer, Sequencer)>
+    kv_index: BTreeMap<Vec<u8>, Entry>,
+    // superseded versions of keys kept by the retention, oldest first
+    history: HashMap<Vec<u8>, Vec<Version>>,
+    // Expired entries taken out by `sweep_expired`, their bytes count as stale. They are kept
+    // until compaction rewrites their records, which has to hide older values of their keys.
+    evicted: HashMap<Vec<u8>, Entry>,
+    // (deadline, key) of the entries that expire, soonest first. An expired entry of a key
+    // with retained versions is left to the retention and only lined up again once they are gone.
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    file_stats: BTreeMap<FileId, FileStats>,
+    retention: Retention,
+    // end of the last record indexed, the log up to here is covered
+    log_end: Option<(FileId, u64)>,
+}
+
+// A version of a key older than its current one, or its removal. A key that was removed
+// has no entry, the removal is then the last of its versions.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Version {
+    pub(crate) lp: LogPointer,
+    pub(crate) sequencer: Sequencer,
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) tombstone: bool,
+}
+
+// How many versions of a key are kept, counting the newest one, and for how long a version
+// is kept after it was superseded whatever their number.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Retention {
+    pub(crate) versions: usize,
+    pub(crate) age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            versions: 1,
+            age: None,
+        }
+    }
+}
+
+impl Retention {
+    fn keeps_history(&self) -> bool {
+        self.versions > 1 || self.age.is_some()
+    }
+}
+
+/// Bytes of a log file still referenced by the index, and bytes that only hold
+/// overwritten values and tombstones.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileStats {
+    pub live_bytes: u64,
+    pub stale_bytes: u64,
+}
+
+impl FileStats {
+    pub fn stale_ratio(&self) -> f64 {
+        let total = self.live_bytes + self.stale_bytes;
+        if total == 0 {
+            0.0
+        } else {
+            self.stale_bytes as f64 / total as f64
+        }
+    }
 }
 
 impl Index {
 
     pub fn new() -> Self {
         Index {
-            kv_index: BTreeMap::new()
+            kv_index: BTreeMap::new(),
+            history: HashMap::new(),
+            evicted: HashMap::new(),
+            expiries: BTreeSet::new(),
+            file_stats: BTreeMap::new(),
+            retention: Retention::default(),
+            log_end: None,
         }
     }
 
+    // rebuilds an index from the parts saved in a checkpoint
+    pub(crate) fn from_parts(kv_index: BTreeMap<Vec<u8>, Entry>,
+                             history: HashMap<Vec<u8>, Vec<Version>>,
+                             evicted: HashMap<Vec<u8>, Entry>,
+                             file_stats: BTreeMap<FileId, FileStats>,
+                             log_end: (FileId, u64)) -> Self {
+        let expiries = kv_index.iter()
+            .filter_map(|(key, (_, _, expires_at))| expires_at.map(|deadline| (deadline, key.clone())))
+            .collect();
+        Index {
+            kv_index,
+            history,
+            evicted,
+            expiries,
+            file_stats,
+            retention: Retention::default(),
+            log_end: Some(log_end),
+        }
+    }
+
+    pub(crate) fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    // every entry in key order
+    pub(crate) fn entries(&self) -> Iter<'_, Vec<u8>, Entry> {
+        self.kv_index.iter()
+    }
+
+    // the newest sequencer of any entry or version
+    pub(crate) fn max_sequencer(&self) -> Option<&Sequencer> {
+        let entries = self.kv_index.values().chain(self.evicted.values()).map(|(_, seq, _)| seq);
+        let versions = self.history.values().flatten().map(|version| &version.sequencer);
+        entries.chain(versions).max_by_key(|seq| seq.timestamp())
+    }
+
+    // `None` as long as nothing was indexed
+    pub(crate) fn log_end(&self) -> Option<&(FileId, u64)> {
+        self.log_end.as_ref()
+    }
+
+    // every entry taken out by `sweep_expired` whose record is still around
+    pub(crate) fn evicted(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
+        self.evicted.iter()
+    }
+
+    // every retained older version, those of a key oldest first
+    pub(crate) fn versions(&self) -> impl Iterator<Item = (&Vec<u8>, &Version)> {
+        self.history.iter().flat_map(|(key, versions)| versions.iter().map(move |version| (key, version)))
+    }
+
     pub fn update_index(&mut self, cmd: &Command, lp: LogPointer) -> Result<()> {
+        let tombstone = matches!(cmd, Command::Rm {..});
+        self.update_entry(cmd.get_key(), cmd.get_sequencer(), tombstone, cmd.get_expires_at(), lp)
+    }
+
+    // same as `update_index` for a record known only by its key and sequencer, as listed in a hint
+    pub(crate) fn update_entry(&mut self, key: &[u8], sequencer: &Sequencer, tombstone: bool,
+                               expires_at: Option<u64>, lp: LogPointer) -> Result<()> {
+        let end = (lp.f_id.clone(), lp.start_pos + lp.len);
+        if self.log_end.as_ref().is_none_or(|log_end| *log_end < end) {
+            self.log_end = Some(end);
+        }
 
-        if let Some((_, seq)) = self.kv_index.get(cmd.get_key()) {
-            // writing a new sequencer
-            if seq.ge(cmd.get_sequencer()) {
+        let latest = match self.kv_index.get(key).or_else(|| self.evicted.get(key)) {
+            Some((_, seq, _)) => Some(seq),
+            None => self.history.get(key).and_then(|versions| versions.last()).map(|version| &version.sequencer),
+        };
+        if latest.is_some_and(|seq| seq.ge(sequencer)) {
+            if !tombstone && !self.retention.keeps_history() {
                 // got conflict
                 return Err(KvError::ConflictError)
             }
+            // compaction may carry a tombstone, or a retained version, into a newer file
+            // than a later write of its key
+            self.stats_mut(&lp.f_id).live_bytes += lp.len;
+            let version = Version {lp, sequencer: sequencer.clone(), expires_at, tombstone};
+            self.retire(key, version);
+            return Ok(());
+        }
+
+        self.stats_mut(&lp.f_id).live_bytes += lp.len;
+        // the bytes of an evicted entry already count as stale
+        self.evicted.remove(key);
+        let replaced = if tombstone {
+            self.kv_index.remove(key)
+        } else {
+            self.kv_index.insert(key.to_vec(), (lp.clone(), sequencer.clone(), expires_at))
+        };
+        if let Some((_, _, Some(deadline))) = &replaced {
+            self.expiries.remove(&(*deadline, key.to_vec()));
+        }
+        if !tombstone {
+            self.track_expiry(key);
+        }
+        if let Some((lp, sequencer, expires_at)) = replaced {
+            self.retire(key, Version {lp, sequencer, expires_at, tombstone: false});
+        }
+        if tombstone {
+            self.retire(key, Version {lp, sequencer: sequencer.clone(), expires_at: None, tombstone});
+        }
+
+        Ok(())
+    }
+
+    // Adds a version of `key` that is no longer its entry to its history, as far as the
+    // retention keeps it. Its bytes must already count as live.
+    fn retire(&mut self, key: &[u8], version: Version) {
+        if !self.retention.keeps_history() {
+            // overwritten values and tombstones are garbage right away
+            let stats = self.stats_mut(&version.lp.f_id);
+            stats.live_bytes -= version.lp.len;
+            stats.stale_bytes += version.lp.len;
+            return;
+        }
+        let versions = self.history.entry(key.to_vec()).or_default();
+        let pos = versions.iter().position(|old| old.sequencer.ge(&version.sequencer)).unwrap_or(versions.len());
+        versions.insert(pos, version);
+        self.prune(key);
+    }
+
+    // drops the versions of `key` the retention no longer keeps
+    fn prune(&mut self, key: &[u8]) {
+        let versions = match self.history.get_mut(key) {
+            Some(versions) => versions,
+            None => return,
+        };
+        let cutoff = self.retention.age.map(|age| unix_nanos().saturating_sub(age.as_nanos()));
+        // the version that superseded the one looked at
+        let mut successor = self.kv_index.get(key).map(|(_, seq, _)| seq);
+        let current = successor.is_some() as usize;
+        let mut kept = 0;
+        for version in versions.iter().rev() {
+            let recent = match (successor, cutoff) {
+                (Some(seq), Some(cutoff)) => seq.timestamp() > cutoff,
+                _ => false,
+            };
+            let newer = current + kept;
+            if newer > 0 && newer >= self.retention.versions && !recent {
+                break;
+            }
+            successor = Some(&version.sequencer);
+            kept += 1;
         }
 
-        match cmd {
-            Command::Rm {..} => {
-                self.kv_index.remove(cmd.get_key());
+        let mut dropped = versions.len() - kept;
+        // with nothing older left, a removal says no more than the key being absent
+        while dropped < versions.len() && versions[dropped].tombstone {
+            dropped += 1;
+        }
+        for version in versions.drain(..dropped) {
+            let stats = self.file_stats.entry(version.lp.f_id).or_default();
+            stats.live_bytes -= version.lp.len;
+            stats.stale_bytes += version.lp.len;
+        }
+        if versions.is_empty() {
+            self.history.remove(key);
+            self.track_expiry(key);
+        }
+    }
+
+    // drops the versions of all keys the retention no longer keeps, as time passes
+    pub(crate) fn prune_history(&mut self) {
+        let keys: Vec<Vec<u8>> = self.history.keys().cloned().collect();
+        for key in keys {
+            self.prune(&key);
+        }
+    }
+
+    // like `prune_history`, only needed with an age limit, every write enforces the count
+    pub(crate) fn prune_aged_history(&mut self) {
+        if self.retention.age.is_some() {
+            self.prune_history();
+        }
+    }
+
+    fn stats_mut(&mut self, f_id: &FileId) -> &mut FileStats {
+        self.file_stats.entry(f_id.clone()).or_default()
+    }
+
+    pub fn file_stats(&self) -> &BTreeMap<FileId, FileStats> {
+        &self.file_stats
+    }
+
+    // sum over all files
+    pub fn total_stats(&self) -> FileStats {
+        self.file_stats.values().fold(FileStats::default(), |total, stats| FileStats {
+            live_bytes: total.live_bytes + stats.live_bytes,
+            stale_bytes: total.stale_bytes + stats.stale_bytes,
+        })
+    }
+
+    // bytes written to `f_id` that the index never points to
+    pub fn add_stale(&mut self, f_id: &FileId, len: u64) {
+        self.stats_mut(f_id).stale_bytes += len;
+    }
+
+    // drops the accounting of a file deleted by compaction
+    pub fn forget_file(&mut self, f_id: &FileId) {
+        self.file_stats.remove(f_id);
+    }
+
+    // the entry of `key` unless it expired by `now`, in milliseconds since the epoch
+    pub(crate) fn get_live(&self, key: &[u8], now: u64) -> Option<&Entry> {
+        self.kv_index.get(key).filter(|(_, _, expires_at)| !is_expired(*expires_at, now))
+    }
+
+    pub fn get_index<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Option<LogPointer> {
+        self.kv_index.get(key.as_ref()).map(|(lp, _, _)| lp.clone())
+    }
+
+    pub(crate) fn has_history(&self, key: &[u8]) -> bool {
+        self.history.contains_key(key)
+    }
+
+    // the entry and retained versions of `key`, newest first
+    pub(crate) fn versions_of(&self, key: &[u8]) -> Vec<Version> {
+        let current = self.kv_index.get(key).map(|(lp, sequencer, expires_at)| {
+            Version {lp: lp.clone(), sequencer: sequencer.clone(), expires_at: *expires_at, tombstone: false}
+        });
+        let older = self.history.get(key).into_iter().flat_map(|versions| versions.iter().rev().cloned());
+        current.into_iter().chain(older).collect()
+    }
+
+    // entries with keys within `bounds`, in key order
+    pub(crate) fn range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Range<'_, Vec<u8>, Entry> {
+        self.kv_index.range::<[u8], _>(bounds)
+    }
+
+    // live entries and retained versions stored in any of `f_ids`
+    pub fn entries_in(&self, f_ids: &BTreeSet<FileId>) -> HashMap<Vec<u8>, Vec<LogPointer>> {
+        let mut entries: HashMap<Vec<u8>, Vec<LogPointer>> = HashMap::new();
+        let current = self.kv_index.iter().map(|(k, (lp, _, _))| (k, lp));
+        let older = self.history.iter().flat_map(|(k, versions)| versions.iter().map(move |version| (k, &version.lp)));
+        for (k, lp) in current.chain(older).filter(|(_, lp)| f_ids.contains(&lp.f_id)) {
+            entries.entry(k.clone()).or_default().push(lp.clone());
+        }
+        entries
+    }
+
+    // point `key` at `new` unless it was overwritten or removed since `old` was read
+    pub fn replace_pointer<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K, old: &LogPointer, new: LogPointer) -> bool {
+        let current = self.kv_index.get_mut(key.as_ref()).map(|(lp, _, _)| lp).into_iter();
+        let older = self.history.get_mut(key.as_ref()).into_iter().flatten().map(|version| &mut version.lp);
+        match current.chain(older).find(|lp| *lp == old) {
+            Some(lp) => {
+                *lp = new.clone();
+                let old_stats = self.file_stats.entry(old.f_id.clone()).or_default();
+                old_stats.live_bytes -= old.len;
+                self.stats_mut(&new.f_id).live_bytes += new.len;
+                true
             },
-            Command::Set {..} => {
-                self.kv_index.insert(cmd.get_key().clone(),
-                                     (lp, cmd.get_sequencer().clone()));
+            None => false
+        }
+    }
+
+    // Drops `key`, whose record at `lp` expired, unless it was written again since. If that
+    // write retired the record as a version, the version goes, its file is about to.
+    pub(crate) fn remove_expired(&mut self, key: &[u8], lp: &LogPointer) {
+        if self.kv_index.get(key).is_some_and(|(current, _, _)| current == lp) {
+            if let Some((_, _, Some(deadline))) = self.kv_index.remove(key) {
+                self.expiries.remove(&(deadline, key.to_vec()));
+            }
+        } else if self.evicted.get(key).is_some_and(|(current, _, _)| current == lp) {
+            self.evicted.remove(key);
+            return;
+        } else if let Some(versions) = self.history.get_mut(key) {
+            match versions.iter().position(|version| version.lp == *lp) {
+                Some(pos) => versions.remove(pos),
+                None => return,
+            };
+            if versions.is_empty() {
+                self.history.remove(key);
+                self.track_expiry(key);
             }
+        } else {
+            return;
         }
+        let stats = self.stats_mut(&lp.f_id);
+        stats.live_bytes -= lp.len;
+        stats.stale_bytes += lp.len;
+    }
 
-        Ok(())
+    // entries taken out by `sweep_expired` whose records are stored in any of `f_ids`
+    pub(crate) fn evicted_in(&self, f_ids: &BTreeSet<FileId>) -> HashMap<Vec<u8>, LogPointer> {
+        self.evicted.iter()
+            .filter(|(_, (lp, _, _))| f_ids.contains(&lp.f_id))
+            .map(|(k, (lp, _, _))| (k.clone(), lp.clone()))
+            .collect()
+    }
+
+    // lines the entry of `key` up to expire, if it does, see `expiries`
+    fn track_expiry(&mut self, key: &[u8]) {
+        if let Some((_, _, Some(deadline))) = self.kv_index.get(key) {
+            self.expiries.insert((*deadline, key.to_vec()));
+        }
+    }
+
+    // whether `sweep_expired` would find anything to do
+    pub(crate) fn expiry_due(&self, now: u64) -> bool {
+        self.expiries.first().is_some_and(|(deadline, _)| is_expired(Some(*deadline), now))
     }
 
-    pub fn get_index(&mut self, key: &String) -> Option<LogPointer> {
-        self.kv_index.get(key).and_then(|(lp, _)| Some(lp.clone()))
+    // Takes the entries that expired by `now` out of the index, so their bytes count as
+    // reclaimable even if their keys are never written again. Keys with retained versions
+    // are left to the retention. Returns whether any entry was taken out.
+    //
+    // Only looks at the entries that are due, not at the whole index.
+    pub(crate) fn sweep_expired(&mut self, now: u64) -> bool {
+        let mut swept = false;
+        while self.expiry_due(now) {
+            let (deadline, key) = self.expiries.pop_first().unwrap();
+            let due = self.kv_index.get(&key).is_some_and(|(_, _, expires_at)| *expires_at == Some(deadline));
+            if !due || self.history.contains_key(&key) {
+                continue;
+            }
+            let entry = self.kv_index.remove(&key).unwrap();
+            let stats = self.stats_mut(&entry.0.f_id);
+            stats.live_bytes -= entry.0.len;
+            stats.stale_bytes += entry.0.len;
+            self.evicted.insert(key, entry);
+            swept = true;
+        }
+        swept
     }
 }
 
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|deadline| deadline <= now)
+}
+
+// the clock in the unit of sequencers
+fn unix_nanos() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos())
+}
+
 impl<'a> IntoIterator for &'a mut Index {
     // not quite sure what is the lifetime of values if I modify the content
-    type Item = (&'a String, &'a mut (LogPointer, Sequencer));
-    type IntoIter = IterMut<'a, String, (LogPointer, Sequencer)>;
+    type Item = (&'a Vec<u8>, &'a mut Entry);
+    type IntoIter = IterMut<'a, Vec<u8>, Entry>;
 
     fn into_iter(self) -> Self::IntoIter {
-        (&mut self.kv_index).iter_mut()
+        self.kv_index.iter_mut()
     }
 }
\ No newline at end of file
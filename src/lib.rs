@@ -1,9 +1,30 @@
 mod error;
+mod engine;
 mod store;
 mod index;
 mod storage;
+mod compactor;
+mod record;
+mod options;
+mod commit;
+mod hint;
+mod checkpoint;
+mod codec;
+mod scan;
+mod batch;
+mod transaction;
 
-pub use store::{KvStore, Command, Sequencer};
+pub use store::{KvStore, Command, Sequencer, CasOutcome};
 pub use error::{Result, KvError};
+pub use engine::KvsEngine;
 pub use storage::{LogPointer, Storage, FileId};
-pub use index::Index;
\ No newline at end of file
+pub use index::{Index, Entry, FileStats};
+pub use options::{KvStoreOptions, SyncPolicy};
+pub use codec::{Codec, JsonCodec};
+pub use scan::Scan;
+pub use batch::{WriteBatch, WriteOp};
+pub use transaction::Transaction;
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "failpoints")]
+pub use storage::CompactionStep;
\ No newline at end of file
@@ -0,0 +1,130 @@
+use crate::index::Retention;
+use std::time::Duration;
+
+/// When appended records are forced to stable storage with `fsync`.
+///
+/// Records are always flushed to the OS before a write returns; the policy only decides
+/// how much acknowledged data a power loss may take with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never fsync, leave it to the OS.
+    #[default]
+    Never,
+    /// Fsync every record before the write returns.
+    EveryWrite,
+    /// Fsync on a write once this much time has passed since the last fsync, and in the
+    /// background for writes still unsynced after about that long. A power loss takes at
+    /// most the writes of roughly the last interval, longer only while a compaction runs.
+    Interval(Duration),
+    /// Fsync on a write once this many bytes were appended since the last fsync.
+    Bytes(u64),
+}
+
+/// Options for opening a `KvStore`, built up from the defaults.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::default()
+///     .max_file_size(64 * 1024 * 1024)
+///     .compaction_file_count(8)
+///     .sync_policy(SyncPolicy::EveryWrite);
+/// let store = KvStore::open_with_options("db", options);
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) max_file_size: u64,
+    pub(crate) compaction_file_count: usize,
+    pub(crate) compaction_stale_ratio: f64,
+    pub(crate) checkpoint_bytes: u64,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) create_if_missing: bool,
+    pub(crate) data_dir: String,
+    pub(crate) retention: Retention,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            max_file_size: 1024 * 32,
+            compaction_file_count: 4,
+            compaction_stale_ratio: 0.5,
+            checkpoint_bytes: 1024 * 1024,
+            sync_policy: SyncPolicy::default(),
+            read_only: false,
+            create_if_missing: true,
+            data_dir: "data".to_owned(),
+            retention: Retention::default(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Size after which the active log file is closed and a new one started.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Compact once there are more log files than this.
+    ///
+    /// Either trigger only fires when there are stale bytes to reclaim.
+    pub fn compaction_file_count(mut self, files: usize) -> Self {
+        self.compaction_file_count = files;
+        self
+    }
+
+    /// Compact once this fraction of all logged bytes is stale, and there is at least
+    /// `max_file_size` worth of stale bytes.
+    pub fn compaction_stale_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_stale_ratio = ratio;
+        self
+    }
+
+    /// Checkpoint the index once this many bytes were appended since the last checkpoint,
+    /// so opening the store only replays what was written after it. `0` disables checkpoints.
+    ///
+    /// A larger index waits for as many bytes as its last checkpoint took, so the cost of
+    /// checkpointing stays proportional to the amount written.
+    pub fn checkpoint_bytes(mut self, bytes: u64) -> Self {
+        self.checkpoint_bytes = bytes;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Open without ever modifying the directory; writes fail with `KvError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether a missing store is created on open, or reported as an error.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Name of the subdirectory holding the log files.
+    pub fn data_dir(mut self, name: impl Into<String>) -> Self {
+        self.data_dir = name.into();
+        self
+    }
+
+    /// Keep up to this many versions of every key, the current one included, for
+    /// `KvStore::history` and `KvStore::get_at`. Removing a key counts as a version.
+    pub fn retain_versions(mut self, versions: usize) -> Self {
+        self.retention.versions = versions.max(1);
+        self
+    }
+
+    /// Also keep every version that was current at some point during the last `age`,
+    /// however many there are.
+    pub fn retain_for(mut self, age: Duration) -> Self {
+        self.retention.age = Some(age);
+        self
+    }
+}
@@ -0,0 +1,397 @@
+use crate::{Command, FileId, LogPointer, Result, Sequencer, KvError};
+use crate::index::Version;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+
+// Every record on disk is framed as
+//
+//   | payload len: u32 LE | crc32 of payload: u32 LE | payload |
+//
+// so a torn write or a flipped byte is detected instead of being parsed as data.
+// Hint files and checkpoints use the same framing for their headers and entries.
+pub(crate) const HEADER_LEN: u64 = 8;
+
+// Log files start with
+//
+//   | magic: 4 bytes | format version: u32 LE |
+//
+// and hold binary commands:
+//
+//   | type: u8 | sequencer: u128 LE | key len: u32 LE | value len: u32 LE | key | value |
+//
+// A set with an expiry has the `EXPIRES` bit set in its type and the deadline, in
+// milliseconds since the Unix epoch, as u64 LE right after the value len.
+//
+// Commands written as one atomic batch are consecutive records, all but the last with the
+// `CONTINUED` bit set in their type. Replay applies them only once the last one is read.
+// Version 1 files predate batches and version 2 files expiry, they are read the same way.
+//
+// Files written before the header existed start right with a record. They hold either framed
+// json commands, or in the very first format, json commands back to back without any framing.
+// The header is only written along with the first record, so a file that was never written
+// to is empty and can be read as any format.
+pub(crate) const FILE_HEADER_LEN: u64 = 8;
+pub(crate) const FORMAT_VERSION: u32 = 3;
+// as the length of a legacy first record this would be well over a gigabyte
+const MAGIC: &[u8; 4] = b"\x89KVS";
+
+const SET: u8 = 0;
+const RM: u8 = 1;
+const CONTINUED: u8 = 0x80;
+const EXPIRES: u8 = 0x40;
+const COMMAND_HEADER_LEN: usize = 1 + 16 + 4 + 4;
+const DEADLINE_LEN: usize = 8;
+
+// Hint files and checkpoints hold index entries rather than commands, binary as well:
+//
+//   | type: u8 | sequencer: u128 LE | file id: u64 LE | offset: u64 LE | len: u64 LE | key |
+//
+// The type is `SET` or `RM` for a tombstone, with the `EXPIRES` bit and deadline as in a
+// command. Their json headers carry `ENTRY_FORMAT`, files from before it are ignored.
+pub(crate) const ENTRY_FORMAT: u32 = 1;
+const ENTRY_HEADER_LEN: usize = 1 + 16 + 8 + 8 + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    // unframed json, only read to upgrade it
+    Stream,
+    Json,
+    Binary,
+}
+
+impl Format {
+    // offset of the first record
+    pub(crate) fn data_start(self) -> u64 {
+        match self {
+            Format::Stream | Format::Json => 0,
+            Format::Binary => FILE_HEADER_LEN,
+        }
+    }
+}
+
+// a command as the json formats stored it, keys and values were strings back then
+#[derive(Deserialize)]
+pub(crate) enum LegacyCommand {
+    Set {key: String, value: String, sequencer: Sequencer},
+    Rm {key: String, sequencer: Sequencer},
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set {key, value, sequencer} => {
+                Command::Set {key: key.into(), value: value.into(), sequencer, expires_at: None}
+            },
+            LegacyCommand::Rm {key, sequencer} => Command::Rm {key: key.into(), sequencer},
+        }
+    }
+}
+
+pub(crate) enum Next<T = Command> {
+    Record(T, u64),
+    // clean end of the file
+    Eof,
+    // the file ends in the middle of a record
+    Incomplete,
+    // the record is complete but does not match its checksum
+    Corrupt,
+}
+
+impl<T> Next<T> {
+    // parses a record, one that does not parse counts as corrupt
+    fn and_then<U>(self, parse: impl FnOnce(T) -> Option<U>) -> Next<U> {
+        match self {
+            Next::Record(payload, len) => match parse(payload) {
+                Some(value) => Next::Record(value, len),
+                None => Next::Corrupt,
+            },
+            Next::Eof => Next::Eof,
+            Next::Incomplete => Next::Incomplete,
+            Next::Corrupt => Next::Corrupt,
+        }
+    }
+}
+
+pub(crate) fn file_header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// format of a log file from its first bytes, or the header version if this build does not know it
+pub(crate) fn detect_format(head: &[u8]) -> std::result::Result<Format, u32> {
+    if head.is_empty() {
+        return Ok(Format::Binary);
+    }
+    if head.len() as u64 == FILE_HEADER_LEN && head.starts_with(MAGIC) {
+        let mut version = [0; 4];
+        version.copy_from_slice(&head[4..]);
+        return match u32::from_le_bytes(version) {
+            1..=FORMAT_VERSION => Ok(Format::Binary),
+            version => Err(version),
+        };
+    }
+    // `{"Set` or `{"Rm`, as a frame length that would be over a gigabyte as well
+    if head.starts_with(b"{\"Se") || head.starts_with(b"{\"Rm") {
+        return Ok(Format::Stream);
+    }
+    Ok(Format::Json)
+}
+
+// `continued` marks a command of a batch that is not the batch's last
+pub(crate) fn encode(cmd: &Command, continued: bool) -> Result<Vec<u8>> {
+    let (kind, value) = match cmd {
+        Command::Set {value, ..} => (SET, value.as_slice()),
+        Command::Rm {..} => (RM, &[][..]),
+    };
+    let key = cmd.get_key();
+    let mut payload = Vec::with_capacity(COMMAND_HEADER_LEN + DEADLINE_LEN + key.len() + value.len());
+    let mut kind = if continued { kind | CONTINUED } else { kind };
+    if cmd.get_expires_at().is_some() {
+        kind |= EXPIRES;
+    }
+    payload.push(kind);
+    payload.extend_from_slice(&cmd.get_sequencer().timestamp().to_le_bytes());
+    // both fit if the whole payload does, see `frame`
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    if let Some(deadline) = cmd.get_expires_at() {
+        payload.extend_from_slice(&deadline.to_le_bytes());
+    }
+    payload.extend_from_slice(key);
+    payload.extend_from_slice(value);
+    frame(&payload)
+}
+
+pub(crate) fn encode_entry(key: &[u8], lp: &LogPointer, sequencer: &Sequencer,
+                           expires_at: Option<u64>, tombstone: bool) -> Result<Vec<u8>> {
+    let mut payload = Vec::with_capacity(ENTRY_HEADER_LEN + DEADLINE_LEN + key.len());
+    let kind = if tombstone { RM } else { SET };
+    payload.push(if expires_at.is_some() { kind | EXPIRES } else { kind });
+    payload.extend_from_slice(&sequencer.timestamp().to_le_bytes());
+    payload.extend_from_slice(&lp.f_id.id.to_le_bytes());
+    payload.extend_from_slice(&lp.start_pos.to_le_bytes());
+    payload.extend_from_slice(&lp.len.to_le_bytes());
+    if let Some(deadline) = expires_at {
+        payload.extend_from_slice(&deadline.to_le_bytes());
+    }
+    payload.extend_from_slice(key);
+    frame(&payload)
+}
+
+pub(crate) fn encode_json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    frame(&serde_json::to_vec(value)?)
+}
+
+// decodes one complete frame as pointed to by a `LogPointer`
+pub(crate) fn decode(frame: &[u8], format: Format) -> Option<Command> {
+    parse_command(check_frame(frame)?, format).map(|(cmd, _)| cmd)
+}
+
+// like `decode`, but leaves only the value of a set command in `frame` instead of building a
+// `Command`, false for a tombstone
+pub(crate) fn decode_value(frame: &mut Vec<u8>, format: Format) -> Option<bool> {
+    let payload = check_frame(frame)?;
+    match format {
+        Format::Binary => {
+            let header = parse_binary_header(payload)?;
+            if header.kind == RM {
+                return Some(false);
+            }
+            frame.drain(..HEADER_LEN as usize + header.value_start);
+        },
+        Format::Stream | Format::Json => match parse_command(payload, format)? {
+            (Command::Set {value, ..}, _) => *frame = value,
+            (Command::Rm {..}, _) => return Some(false),
+        },
+    }
+    Some(true)
+}
+
+// Reads the next command from a log being replayed, `remaining` is what is left of the file.
+// Along with the command comes whether more commands of its batch follow.
+pub(crate) fn read_next<R: Read>(reader: &mut R, remaining: u64, format: Format) -> io::Result<Next<(Command, bool)>> {
+    Ok(read_frame(reader, remaining)?.and_then(|payload| parse_command(&payload, format)))
+}
+
+// reads the next entry of a hint file or checkpoint, as the key and its version
+pub(crate) fn read_next_entry<R: Read>(reader: &mut R, remaining: u64) -> io::Result<Next<(Vec<u8>, Version)>> {
+    Ok(read_frame(reader, remaining)?.and_then(|payload| parse_entry(&payload)))
+}
+
+pub(crate) fn read_next_json<R: Read, T: DeserializeOwned>(reader: &mut R, remaining: u64) -> io::Result<Next<T>> {
+    Ok(read_frame(reader, remaining)?.and_then(|payload| serde_json::from_slice(&payload).ok()))
+}
+
+// fails with `KvError::InvalidArgument` if the payload is too large for its length field
+fn frame(payload: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(payload.len()).map_err(|_| KvError::InvalidArgument)?;
+    let mut frame = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+// the checked payload of the next frame
+fn read_frame<R: Read>(reader: &mut R, remaining: u64) -> io::Result<Next<Vec<u8>>> {
+    if remaining == 0 {
+        return Ok(Next::Eof);
+    }
+    if remaining < HEADER_LEN {
+        return Ok(Next::Incomplete);
+    }
+
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let (len, crc) = parse_header(&header);
+    if HEADER_LEN + len > remaining {
+        return Ok(Next::Incomplete);
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(if crc32fast::hash(&payload) == crc {
+        Next::Record(payload, HEADER_LEN + len)
+    } else {
+        Next::Corrupt
+    })
+}
+
+// the payload of a complete frame, if it matches its checksum
+fn check_frame(frame: &[u8]) -> Option<&[u8]> {
+    if (frame.len() as u64) < HEADER_LEN {
+        return None;
+    }
+    let (len, crc) = parse_header(&frame[..HEADER_LEN as usize]);
+    let payload = &frame[HEADER_LEN as usize..];
+    if payload.len() as u64 != len || crc32fast::hash(payload) != crc {
+        return None;
+    }
+    Some(payload)
+}
+
+fn parse_header(header: &[u8]) -> (u64, u32) {
+    let mut len = [0; 4];
+    let mut crc = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    crc.copy_from_slice(&header[4..8]);
+    (u32::from_le_bytes(len) as u64, u32::from_le_bytes(crc))
+}
+
+fn parse_command(payload: &[u8], format: Format) -> Option<(Command, bool)> {
+    match format {
+        Format::Stream | Format::Json => {
+            serde_json::from_slice::<LegacyCommand>(payload).ok().map(|cmd| (cmd.into(), false))
+        },
+        Format::Binary => parse_binary(payload),
+    }
+}
+
+fn parse_binary(payload: &[u8]) -> Option<(Command, bool)> {
+    let header = parse_binary_header(payload)?;
+    let key = payload[header.value_start - header.key_len..header.value_start].to_vec();
+    let sequencer = header.sequencer;
+    let cmd = match header.kind {
+        SET => Command::Set {key, value: payload[header.value_start..].to_vec(), sequencer, expires_at: header.expires_at},
+        _ => Command::Rm {key, sequencer},
+    };
+    Some((cmd, header.continued))
+}
+
+fn parse_entry(payload: &[u8]) -> Option<(Vec<u8>, Version)> {
+    if payload.len() < ENTRY_HEADER_LEN {
+        return None;
+    }
+    let tombstone = match payload[0] & !EXPIRES {
+        SET => false,
+        RM => true,
+        _ => return None,
+    };
+    let mut timestamp = [0; 16];
+    timestamp.copy_from_slice(&payload[1..17]);
+    let lp = LogPointer {
+        f_id: FileId {id: le_u64(&payload[17..25])},
+        start_pos: le_u64(&payload[25..33]),
+        len: le_u64(&payload[33..41]),
+    };
+
+    let mut key_start = ENTRY_HEADER_LEN;
+    let mut expires_at = None;
+    if payload[0] & EXPIRES != 0 {
+        if payload.len() < ENTRY_HEADER_LEN + DEADLINE_LEN {
+            return None;
+        }
+        expires_at = Some(le_u64(&payload[key_start..key_start + DEADLINE_LEN]));
+        key_start += DEADLINE_LEN;
+    }
+    let version = Version {
+        lp,
+        sequencer: Sequencer::from_timestamp(u128::from_le_bytes(timestamp)),
+        expires_at,
+        tombstone,
+    };
+    Some((payload[key_start..].to_vec(), version))
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes);
+    u64::from_le_bytes(value)
+}
+
+struct BinaryHeader {
+    kind: u8,
+    sequencer: Sequencer,
+    expires_at: Option<u64>,
+    continued: bool,
+    key_len: usize,
+    // offset of the value in the payload
+    value_start: usize,
+}
+
+fn parse_binary_header(payload: &[u8]) -> Option<BinaryHeader> {
+    if payload.len() < COMMAND_HEADER_LEN {
+        return None;
+    }
+    let mut timestamp = [0; 16];
+    timestamp.copy_from_slice(&payload[1..17]);
+    let mut key_len = [0; 4];
+    key_len.copy_from_slice(&payload[17..21]);
+    let mut value_len = [0; 4];
+    value_len.copy_from_slice(&payload[21..25]);
+    let key_len = u32::from_le_bytes(key_len) as usize;
+    let value_len = u32::from_le_bytes(value_len) as usize;
+
+    let mut key_start = COMMAND_HEADER_LEN;
+    let mut expires_at = None;
+    if payload[0] & EXPIRES != 0 {
+        if payload.len() < COMMAND_HEADER_LEN + DEADLINE_LEN {
+            return None;
+        }
+        let mut deadline = [0; DEADLINE_LEN];
+        deadline.copy_from_slice(&payload[key_start..key_start + DEADLINE_LEN]);
+        expires_at = Some(u64::from_le_bytes(deadline));
+        key_start += DEADLINE_LEN;
+    }
+    if payload.len() != key_start + key_len + value_len {
+        return None;
+    }
+
+    let kind = match payload[0] & !(CONTINUED | EXPIRES) {
+        SET => SET,
+        RM if value_len == 0 && expires_at.is_none() => RM,
+        _ => return None,
+    };
+    Some(BinaryHeader {
+        kind,
+        sequencer: Sequencer::from_timestamp(u128::from_le_bytes(timestamp)),
+        expires_at,
+        continued: payload[0] & CONTINUED != 0,
+        key_len,
+        value_start: key_start + key_len,
+    })
+}
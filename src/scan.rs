@@ -0,0 +1,143 @@
+use crate::{Index, KvError, Result, Storage};
+use crate::index::is_expired;
+use crate::store::unix_millis;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, RwLock};
+
+/// Iterator over the entries of a `KvStore` within a key range, in key order, created by
+/// `KvStore::scan` and `KvStore::scan_bytes`.
+///
+/// Values are read from the log only as the iterator advances. Every step looks the next key
+/// up in the live index, so writes made while scanning are seen if they land ahead of the scan.
+pub struct Scan<T> {
+    storage: Arc<Storage>,
+    index: Arc<RwLock<Index>>,
+    // the part of the range not returned yet, from either end
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    remaining: Option<usize>,
+    // whether to read values at all, keys only scans hand an empty value to `convert`
+    values: bool,
+    convert: fn(Vec<u8>, Vec<u8>) -> Result<T>,
+}
+
+impl<T> Scan<T> {
+    pub(crate) fn new<K: AsRef<[u8]>>(storage: Arc<Storage>, index: Arc<RwLock<Index>>, range: impl RangeBounds<K>,
+                                      convert: fn(Vec<u8>, Vec<u8>) -> Result<T>) -> Scan<T> {
+        Scan {
+            storage,
+            index,
+            start: to_owned_bound(range.start_bound()),
+            end: to_owned_bound(range.end_bound()),
+            remaining: None,
+            values: true,
+            convert,
+        }
+    }
+
+    pub(crate) fn keys_only(mut self) -> Scan<T> {
+        self.values = false;
+        self
+    }
+
+    /// Stops after `limit` entries, counting those taken from both ends.
+    pub fn limit(mut self, limit: usize) -> Scan<T> {
+        self.remaining = Some(limit);
+        self
+    }
+
+    fn step(&mut self, forward: bool) -> Option<Result<T>> {
+        if self.remaining == Some(0) || self.exhausted() {
+            return None;
+        }
+        let now = match unix_millis() {
+            Ok(now) => now,
+            Err(e) => return Some(Err(e)),
+        };
+        // the index is released before reading, see `KvStore::get_versioned`
+        let (key, value, read) = loop {
+            let (key, lp) = {
+                let index = self.index.read().unwrap();
+                let mut entries = index.range((as_slice_bound(&self.start), as_slice_bound(&self.end)))
+                    .filter(|(_, (_, _, expires_at))| !is_expired(*expires_at, now));
+                let (key, (lp, _, _)) = if forward { entries.next()? } else { entries.next_back()? };
+                (key.clone(), lp.clone())
+            };
+
+            let mut value = Vec::new();
+            let read = if self.values { self.storage.try_read_value(&lp, &mut value) } else { Ok(Some(true)) };
+            // nothing read if compaction deleted the file meanwhile, the key moved
+            if let Some(read) = read.transpose() {
+                break (key, value, read);
+            }
+        };
+
+        if forward {
+            self.start = Bound::Excluded(key.clone());
+        } else {
+            self.end = Bound::Excluded(key.clone());
+        }
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Some(match read {
+            Ok(true) => (self.convert)(key, value),
+            Ok(false) => Err(KvError::KeyNotFound),
+            Err(e) => Err(e),
+        })
+    }
+
+    // whether nothing can be left between the bounds, `BTreeMap::range` panics on such ranges
+    fn exhausted(&self) -> bool {
+        use Bound::*;
+        match (&self.start, &self.end) {
+            (Included(start), Included(end)) => start > end,
+            (Included(start), Excluded(end)) | (Excluded(start), Included(end)) | (Excluded(start), Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+}
+
+impl<T> Iterator for Scan<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        self.step(true)
+    }
+}
+
+impl<T> DoubleEndedIterator for Scan<T> {
+    fn next_back(&mut self) -> Option<Result<T>> {
+        self.step(false)
+    }
+}
+
+// the range of keys starting with `prefix`
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // the first key past the prefix is the prefix with its last byte that can be
+    // incremented incremented, and nothing after it
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+fn to_owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn as_slice_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
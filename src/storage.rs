@@ -1,5 +1,6 @@
-use crate::{Result, KvError, Index};
-use crate::store::{Command, Sequencer};
+use crate::{Result, KvError, Index, FileStats, KvStoreOptions, SyncPolicy};
+use crate::store::{Command, Sequencer, unix_millis};
+use crate::index::is_expired;
 use std::path::{PathBuf, Path};
 use std::fs;
 use std::fs::{File, OpenOptions};
@@ -8,156 +9,885 @@ use std::io::{Read, Seek, BufReader, SeekFrom, Write, BufWriter};
 use std::fmt::Display;
 use failure::_core::fmt::Formatter;
 use std::ffi::OsStr;
-use failure::_core::cmp::Ordering;
-use std::collections::{BTreeMap};
-use serde_json::Deserializer;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::record;
+use crate::record::{Next, Format, LegacyCommand};
+use crate::hint;
+use crate::hint::{HintEntry, HINT_EXT};
+use crate::checkpoint::Checkpoint;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq)]
+// Ends a compaction early if a crash was asked for right after `$step`, see `compaction_until`.
+macro_rules! crash_point {
+    ($storage:expr, $step:expr) => {
+        #[cfg(any(test, feature = "failpoints"))]
+        {
+            if *$storage.crash_after.lock().unwrap() == Some($step) {
+                return Ok(());
+            }
+        }
+    };
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LogPointer {
     pub start_pos: u64,
     pub len: u64,
     pub f_id: FileId,
 }
 
+// Readers share one file handle per log file and use positional reads, so any number of
+// threads can `get` at the same time. Appends go through the single writer behind a mutex.
 pub struct Storage {
     storage_path: PathBuf,
-    readers: BTreeMap<FileId, BufferedReaderWithPos<File>>,
+    readers: RwLock<BTreeMap<FileId, Arc<LogFile>>>,
+    // `None` when opened read-only
+    writer: Mutex<Option<ActiveLog>>,
+    // appended since the index was last checkpointed
+    uncheckpointed_bytes: AtomicU64,
+    // size of the last checkpoint, writing one is not worth it before that much was appended
+    checkpoint_len: AtomicU64,
+    // one checkpoint at a time, they are written under the same temporary name
+    checkpointing: Mutex<()>,
+    options: KvStoreOptions,
+    #[cfg(any(test, feature = "failpoints"))]
+    crash_after: Mutex<Option<CompactionStep>>,
+}
+
+// an open log file and the record format it was written in
+struct LogFile {
+    file: File,
+    format: Format,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<LogFile> {
+        let file = File::open(path)?;
+        match LogFile::read_format(&file, path)? {
+            Format::Stream => Err(KvError::UpgradeRequired(path.to_owned())),
+            format => Ok(LogFile {file, format}),
+        }
+    }
+
+    fn read_format(file: &File, path: &Path) -> Result<Format> {
+        let mut head = vec![0; file.metadata()?.len().min(record::FILE_HEADER_LEN) as usize];
+        read_exact_at(file, &mut head, 0)?;
+        record::detect_format(&head).map_err(|version| KvError::UnsupportedVersion {
+            file: path.to_owned(),
+            version,
+        })
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+struct ActiveLog {
     writer: BufferedWriterWithPos<File>,
     current_f_id: FileId,
+    // bytes appended and time of the last fsync, for the periodic sync policies
+    unsynced_bytes: u64,
+    last_sync: Instant,
 }
 
 impl Storage {
-    pub const MAX_LOG_SIZE: u64 = 1024 * 32;
+    pub fn new(path: &Path) -> Result<Storage> {
+        Storage::with_options(path, &KvStoreOptions::default())
+    }
+
+    pub fn with_options(path: &Path, options: &KvStoreOptions) -> Result<Storage> {
+        let storage_path = path.join(&options.data_dir);
+
+        if !storage_path.is_dir() {
+            if options.read_only || !options.create_if_missing {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("no store found at {:?}", storage_path)).into());
+            }
+            fs::create_dir_all(&storage_path)?;
+        }
 
-    pub fn new(path: &PathBuf) -> Result<Storage> {
-        let mut storage_path = path.clone();
-        storage_path.push("data");
+        let mut readers: BTreeMap<FileId, Arc<LogFile>> = BTreeMap::new();
+        if options.read_only {
+            // an interrupted compaction cannot be finished without writing, read around it
+            let manifest = CompactionManifest::read(&storage_path)?;
+            for f_id in Storage::sorted_f_id_list(&storage_path)? {
+                if manifest.as_ref().is_some_and(|m| m.obsolete.contains(&f_id)) {
+                    continue;
+                }
+                readers.insert(f_id.clone(), Arc::new(LogFile::open(&Storage::log_path(&f_id, &storage_path))?));
+            }
+            if let Some(manifest) = manifest {
+                let compaction_path = Storage::compaction_path(&manifest.output, &storage_path);
+                if compaction_path.exists() {
+                    readers.insert(manifest.output, Arc::new(LogFile::open(&compaction_path)?));
+                }
+            }
+
+            return Ok(Storage {
+                storage_path,
+                readers: RwLock::new(readers),
+                writer: Mutex::new(None),
+                uncheckpointed_bytes: AtomicU64::new(0),
+                checkpoint_len: AtomicU64::new(0),
+                checkpointing: Mutex::new(()),
+                options: options.clone(),
+                #[cfg(any(test, feature = "failpoints"))]
+                crash_after: Mutex::new(None),
+            });
+        }
+
+        Storage::recover_compaction(&storage_path)?;
 
-        fs::create_dir_all(&storage_path)?;
-        let mut readers: BTreeMap<FileId, BufferedReaderWithPos<File>> = BTreeMap::new();
         let sorted_f_id_l = Storage::sorted_f_id_list(&storage_path)?;
         for f_id in &sorted_f_id_l {
             readers.insert(f_id.clone(),
-                           BufferedReaderWithPos::new(
-                               File::open(Storage::log_path(&f_id, &storage_path))?)?
-            );
+                           Arc::new(LogFile::open(&Storage::log_path(f_id, &storage_path))?));
         }
 
         let writer_id = sorted_f_id_l.last().unwrap_or(&FileId {id: 0}).inc();
         let writer = Storage::new_log_file(&writer_id, &storage_path, &mut readers)?;
+        if options.sync_policy != SyncPolicy::Never {
+            sync_dir(&storage_path)?;
+        }
 
+        let checkpoint_len = Checkpoint::len(&storage_path)?;
         Ok(Storage {
             storage_path,
-            readers,
-            writer,
-            current_f_id: writer_id,
+            readers: RwLock::new(readers),
+            writer: Mutex::new(Some(ActiveLog {
+                writer,
+                current_f_id: writer_id,
+                unsynced_bytes: 0,
+                last_sync: Instant::now(),
+            })),
+            uncheckpointed_bytes: AtomicU64::new(0),
+            checkpoint_len: AtomicU64::new(checkpoint_len),
+            checkpointing: Mutex::new(()),
+            options: options.clone(),
+            #[cfg(any(test, feature = "failpoints"))]
+            crash_after: Mutex::new(None),
         })
     }
 
-    pub fn get(&mut self, lp: &LogPointer) -> Result<Command> {
-        if let Some(reader) = self.readers.get_mut(&lp.f_id) {
-            reader.seek(SeekFrom::Start(lp.start_pos))?;
-            // this is reading from reader with a fixed lenth
-            // when we do loading at start, it is like reading in a stream way
-            let cmd_reader = reader.take(lp.len);
+    pub fn get(&self, lp: &LogPointer) -> Result<Command> {
+        let mut buf = Vec::new();
+        let format = self.read_record(lp, &mut buf)?.ok_or(KvError::KeyNotFound)?;
+        record::decode(&buf, format).ok_or_else(|| self.corruption(lp))
+    }
+
+    // reads the value of the set command at `lp` into `buf`, false if it is a tombstone
+    pub fn read_value(&self, lp: &LogPointer, buf: &mut Vec<u8>) -> Result<bool> {
+        self.try_read_value(lp, buf)?.ok_or(KvError::KeyNotFound)
+    }
 
-            let cmd: Command = serde_json::from_reader(cmd_reader)?;
-            Ok(cmd)
+    // Like `read_value`, `None` if the file of `lp` is gone. Readers look pointers up and
+    // release the index before reading, compaction may delete the file in between; the key
+    // then has a pointer into the compacted file.
+    pub(crate) fn try_read_value(&self, lp: &LogPointer, buf: &mut Vec<u8>) -> Result<Option<bool>> {
+        match self.read_record(lp, buf)? {
+            Some(format) => record::decode_value(buf, format).map(Some).ok_or_else(|| self.corruption(lp)),
+            None => Ok(None),
+        }
+    }
+
+    // reads the frame at `lp` into `buf`, returning the format of its file
+    fn read_record(&self, lp: &LogPointer, buf: &mut Vec<u8>) -> Result<Option<Format>> {
+        let log = self.readers.read().unwrap().get(&lp.f_id).cloned();
+        if let Some(log) = log {
+            // positional read, the shared file cursor is never moved, and the handle keeps
+            // the file readable even if compaction deletes it meanwhile
+            buf.clear();
+            buf.resize(lp.len as usize, 0);
+            read_exact_at(&log.file, buf, lp.start_pos)?;
+            Ok(Some(log.format))
         } else {
-            Err(KvError::KeyNotFound)
+            Ok(None)
         }
+    }
 
+    fn corruption(&self, lp: &LogPointer) -> KvError {
+        KvError::Corruption {
+            file: Storage::log_path(&lp.f_id, &self.storage_path),
+            offset: lp.start_pos,
+        }
     }
 
-    pub fn build_index(&mut self, index: &mut Index) -> Result<()> {
-        for (f_id, reader) in self.readers.iter_mut() {
-            let mut pos = reader.seek(SeekFrom::Start(0))?;
+    // Loads the index checkpoint if it still matches the files on disk and replays the log
+    // written after it, or the whole log otherwise.
+    pub fn build_index(&self, index: &mut Index) -> Result<()> {
+        let readers = self.readers.read().unwrap();
 
-            // WTF! How can this reader ref get into that Deserializer::from_reader
-            // not 100% percent sure, but maybe related to how the trait is defined for references
-            // https://stackoverflow.com/questions/44928882/why-do-i-get-the-error-the-trait-foo-is-not-implemented-for-mut-t-even-th
-            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-            while let Some(cmd) = stream.next() {
-                let new_pos = stream.byte_offset() as u64;
-                index.update_index(&cmd?, LogPointer {start_pos: pos, len: new_pos - pos, f_id: f_id.clone()})?;
+        let mut replay_from = None;
+        if let Some(checkpoint) = Checkpoint::read(&self.storage_path)? {
+            if Storage::checkpoint_matches(&readers, &checkpoint)? {
+                replay_from = Some((checkpoint.f_id, checkpoint.offset));
+                *index = checkpoint.index;
+            } else {
+                log::info!("index checkpoint is out of date, replaying the whole log");
+            }
+        }
+        // the checkpoint may hold versions an earlier, longer retention kept
+        index.set_retention(self.options.retention.clone());
+        index.prune_history();
 
-                pos = new_pos;
+        // A crash in the middle of an append can only tear the newest file that has data,
+        // every file after it was created empty by a later open or roll.
+        let mut tail_f_id = None;
+        for (f_id, log) in readers.iter() {
+            if log.len()? > 0 {
+                tail_f_id = Some(f_id.clone());
             }
         }
 
+        for (f_id, log) in readers.iter() {
+            let start_pos = match &replay_from {
+                Some((from_f_id, _)) if f_id < from_f_id => continue,
+                Some((from_f_id, offset)) if f_id == from_f_id => *offset,
+                _ => 0,
+            };
+            let file_len = log.len()?;
+            // a hint covers the whole file, the checkpoint may already cover part of it
+            let hint = match start_pos {
+                0 => hint::read(&Storage::hint_path(f_id, &self.storage_path), file_len)?,
+                _ => None,
+            };
+            if let Some(entries) = hint {
+                for entry in entries {
+                    let version = entry.version;
+                    // the entries point into the file the hint sits next to, whatever they say
+                    let lp = LogPointer {f_id: f_id.clone(), ..version.lp};
+                    replay_entry(index, &entry.key, &version.sequencer, version.tombstone, version.expires_at, lp)?;
+                }
+                continue;
+            }
+
+            let mut reader = BufferedReaderWithPos::new(&log.file, start_pos.max(log.format.data_start()))?;
+            // commands of a batch whose last command was not read yet
+            let mut pending = Vec::new();
+
+            loop {
+                let pos = reader.pos;
+                match record::read_next(&mut reader, file_len.saturating_sub(pos), log.format)? {
+                    Next::Record((cmd, continued), len) => {
+                        pending.push((cmd, LogPointer {start_pos: pos, len, f_id: f_id.clone()}));
+                        if !continued {
+                            for (cmd, lp) in pending.drain(..) {
+                                let tombstone = matches!(cmd, Command::Rm {..});
+                                replay_entry(index, cmd.get_key(), cmd.get_sequencer(), tombstone, cmd.get_expires_at(), lp)?;
+                            }
+                        }
+                    },
+                    Next::Eof if pending.is_empty() => break,
+                    Next::Eof | Next::Incomplete if tail_f_id.as_ref() == Some(f_id) => {
+                        // a batch cut short is dropped as a whole, together with the torn record
+                        let pos = pending.first().map_or(pos, |(_, lp)| lp.start_pos);
+                        if self.options.read_only {
+                            log::warn!("ignoring incomplete record at offset {} in {:?}",
+                                       pos, Storage::log_path(f_id, &self.storage_path));
+                        } else {
+                            self.truncate_torn_tail(f_id, pos, file_len)?;
+                        }
+                        break;
+                    },
+                    Next::Eof | Next::Incomplete | Next::Corrupt => {
+                        return Err(KvError::Corruption {
+                            file: Storage::log_path(f_id, &self.storage_path),
+                            offset: pos,
+                        });
+                    }
+                }
+            }
+        }
+
+        // new writes have to be newer than anything in the log, even if the clock that wrote
+        // it was ahead of ours, or every write to those keys would conflict
+        if let Some(sequencer) = index.max_sequencer() {
+            Sequencer::observe(sequencer);
+        }
+        Ok(())
+    }
+
+    // A checkpoint is only usable if none of the files before its position changed since, and
+    // its own file still holds everything up to the position.
+    fn checkpoint_matches(readers: &BTreeMap<FileId, Arc<LogFile>>, checkpoint: &Checkpoint) -> Result<bool> {
+        let mut files = Vec::new();
+        for (f_id, log) in readers.range(..&checkpoint.f_id) {
+            files.push((f_id.clone(), log.len()?));
+        }
+        let covered = match readers.get(&checkpoint.f_id) {
+            Some(log) => log.len()? >= checkpoint.offset,
+            None => false,
+        };
+        Ok(covered && files == checkpoint.files)
+    }
+
+    // drops an incomplete record left at the end of a file by an interrupted append
+    fn truncate_torn_tail(&self, f_id: &FileId, pos: u64, file_len: u64) -> Result<()> {
+        let path = Storage::log_path(f_id, &self.storage_path);
+        log::warn!("discarding incomplete record of {} bytes at offset {} in {:?}",
+                   file_len - pos, pos, path);
+
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(pos)?;
+        file.sync_all()?;
         Ok(())
     }
 
-    pub fn mutate(&mut self, cmd: Command) -> Result<LogPointer> {
-        let start_pos = self.writer.pos;
+    pub fn mutate(&self, cmd: Command) -> Result<LogPointer> {
+        self.mutate_batch(&[vec![cmd]], |mut lps| Ok(lps.remove(0)))
+    }
 
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    // Appends all commands with a single write and a single flush, and syncs them together
+    // as the sync policy requires. All of them land in the same file. Each group of commands
+    // is an atomic batch, replay applies either all of a group or none of it.
+    //
+    // `on_appended` gets the new pointers while the writer is still held. Indexing them there
+    // means compaction, which rolls the log under the same lock, never snapshots the index
+    // while records it is about to make obsolete are still on their way into it.
+    pub fn mutate_batch<F, R>(&self, groups: &[Vec<Command>], on_appended: F) -> Result<R>
+        where F: FnOnce(Vec<LogPointer>) -> Result<R> {
+        let mut writer = self.writer.lock().unwrap();
+        let active = writer.as_mut().ok_or(KvError::ReadOnly)?;
+        let start_pos = active.writer.pos;
 
-        let new_pos = self.writer.pos;
+        // a fresh file gets its header with the first records, see `record`
+        let mut buf = match start_pos {
+            0 => record::file_header(),
+            _ => Vec::new(),
+        };
+        let mut lps = Vec::new();
+        for group in groups {
+            for (i, cmd) in group.iter().enumerate() {
+                let frame = record::encode(cmd, i + 1 < group.len())?;
+                lps.push(LogPointer {
+                    start_pos: start_pos + buf.len() as u64,
+                    len: frame.len() as u64,
+                    f_id: active.current_f_id.clone(),
+                });
+                buf.extend_from_slice(&frame);
+            }
+        }
+
+        active.writer.write_all(&buf)?;
+        active.writer.flush()?;
 
-        let lp = LogPointer {
-            start_pos,
-            len: new_pos - start_pos,
-            f_id: self.current_f_id.clone()
+        let new_pos = active.writer.pos;
+        active.unsynced_bytes += new_pos - start_pos;
+        self.uncheckpointed_bytes.fetch_add(new_pos - start_pos, Ordering::SeqCst);
+        let should_sync = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Interval(interval) => active.last_sync.elapsed() >= interval,
+            SyncPolicy::Bytes(bytes) => active.unsynced_bytes >= bytes,
         };
+        if should_sync {
+            Storage::sync_active(active)?;
+        }
 
-        if new_pos > Storage::MAX_LOG_SIZE {
-            let writer_id = self.current_f_id.inc();
-            let writer = Storage::new_log_file(&writer_id, &self.storage_path, &mut self.readers)?;
-            self.current_f_id = writer_id;
-            self.writer = writer;
+        let appended = on_appended(lps)?;
+
+        if new_pos > self.options.max_file_size {
+            self.roll(active)?;
+        }
+
+        Ok(appended)
+    }
+
+    // switch the active log to a fresh file, the old one becomes immutable
+    fn roll(&self, active: &mut ActiveLog) -> Result<()> {
+        if self.options.sync_policy != SyncPolicy::Never {
+            // whatever the policy still owes to the old file is due now, it is never written again
+            Storage::sync_active(active)?;
+        }
+
+        let writer_id = active.current_f_id.inc();
+        let mut readers = self.readers.write().unwrap();
+        active.writer = Storage::new_log_file(&writer_id, &self.storage_path, &mut readers)?;
+        active.current_f_id = writer_id;
+        if self.options.sync_policy != SyncPolicy::Never {
+            sync_dir(&self.storage_path)?;
         }
 
-        Ok(lp)
+        Ok(())
     }
 
-    pub fn should_compaction(&mut self) -> bool {
-        self.readers.len() > 4
+    // the interval of `SyncPolicy::Interval`, writes must not stay unsynced much longer
+    pub(crate) fn sync_interval(&self) -> Option<Duration> {
+        match self.options.sync_policy {
+            SyncPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        }
     }
 
-    pub fn compaction(&mut self, index: &mut Index) -> Result<()> {
-        // should make this async later
-        let stop_f_id = self.current_f_id.clone();
+    // Syncs what the interval policy left unsynced, a write only syncs once the interval has
+    // passed and the last one of a burst may never see that.
+    pub(crate) fn sync_pending(&self) -> Result<()> {
+        if self.sync_interval().is_some() {
+            if let Some(active) = self.writer.lock().unwrap().as_mut() {
+                if active.unsynced_bytes > 0 {
+                    Storage::sync_active(active)?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let writer_id = self.current_f_id.inc();
-        let writer = Storage::new_log_file(&writer_id, &self.storage_path, &mut self.readers)?;
-        self.current_f_id = writer_id;
-        self.writer = writer;
+    /// Bytes appended since the last fsync, those a power loss could take away.
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.writer.lock().unwrap().as_ref().map_or(0, |active| active.unsynced_bytes)
+    }
 
-        for (_, v) in index.into_iter() {
-            let lp: &LogPointer = &v.0;
-            let seq: &Sequencer = &v.1;
-            let cmd = self.get(lp)?;
-            let lp_updated = self.mutate(cmd)?;
+    fn sync_active(active: &mut ActiveLog) -> Result<()> {
+        active.writer.sync()?;
+        active.unsynced_bytes = 0;
+        active.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // takes expired entries out of the index, true if there were any
+    pub(crate) fn sweep_expired(&self, index: &RwLock<Index>) -> Result<bool> {
+        let now = unix_millis()?;
+        // most of the time nothing is due, and writers need not wait for finding that out
+        if !index.read().unwrap().expiry_due(now) {
+            return Ok(false);
+        }
+        Ok(index.write().unwrap().sweep_expired(now))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    // Compaction is only worth it when there is garbage to reclaim, a write-once dataset
+    // is never rewritten however many files it spans.
+    pub fn should_compaction(&self, index: &Index) -> bool {
+        if self.options.read_only {
+            return false;
+        }
+
+        let total = index.total_stats();
+        if total.stale_bytes == 0 {
+            return false;
+        }
+        let too_many_files = self.readers.read().unwrap().len() > self.options.compaction_file_count;
+        // a tiny dataset with a high ratio is not worth a rewrite before a file's worth of garbage
+        let too_much_garbage = total.stale_bytes >= self.options.max_file_size
+            && total.stale_ratio() >= self.options.compaction_stale_ratio;
+
+        too_many_files || too_much_garbage
+    }
+
+    pub fn checkpoints_enabled(&self) -> bool {
+        self.options.checkpoint_bytes > 0 && !self.options.read_only
+    }
+
+    // A checkpoint costs as much as the index is large, so as the index grows it takes
+    // proportionally more writes to trigger the next one.
+    pub fn should_checkpoint(&self) -> bool {
+        let threshold = self.options.checkpoint_bytes.max(self.checkpoint_len.load(Ordering::SeqCst));
+        self.checkpoints_enabled() && self.uncheckpointed_bytes.load(Ordering::SeqCst) >= threshold
+    }
 
-            // want to get rid of this clone, but seems not possible
-            *v = (lp_updated, seq.clone());
+    // Saves a copy of the index together with the log position it covers.
+    //
+    // Writers are not held off at all. Records are indexed in log order under the writer lock,
+    // so a copy of the index covers the log exactly up to the end of the last record it saw.
+    pub fn checkpoint(&self, index: &RwLock<Index>) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvError::ReadOnly);
         }
+        let _checkpointing = self.checkpointing.lock().unwrap();
+        let uncheckpointed = self.uncheckpointed_bytes.load(Ordering::SeqCst);
 
+        // Taken before the index is copied: a file compaction installs in between is missing
+        // from the list, which makes the checkpoint look out of date rather than match files
+        // the copy no longer points into.
+        let logs = self.readers.read().unwrap().clone();
+        let index = index.read().unwrap().clone();
+        let (f_id, offset) = match index.log_end() {
+            Some(log_end) => log_end.clone(),
+            None => return Ok(()),
+        };
+        let mut files = Vec::new();
+        for (f_id, log) in logs.range(..&f_id) {
+            files.push((f_id.clone(), log.len()?));
+        }
+        // the checkpoint must not cover records a power loss could still take away
+        let log = match logs.get(&f_id) {
+            Some(log) => Some(log.clone()),
+            // rolled over to since the files were listed
+            None => self.readers.read().unwrap().get(&f_id).cloned(),
+        };
+        match log {
+            Some(log) => log.file.sync_data()?,
+            // compacted away already, the checkpoint would be out of date anyway
+            None => return Ok(()),
+        }
+
+        let checkpoint = Checkpoint {f_id, offset, files, index};
+        let len = checkpoint.write(&self.storage_path)?;
+        self.checkpoint_len.store(len, Ordering::SeqCst);
+        self.uncheckpointed_bytes.fetch_sub(uncheckpointed, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Rewrites the live records of the immutable files carrying the most garbage into a new file.
+    //
+    // Writers are only blocked while the active file is rolled over, the copy itself runs
+    // against the immutable files while new writes keep going to the new active file.
+    // Pointers are swapped in the index only if no newer write has replaced them meanwhile,
+    // and the old files are deleted after the swap, so readers never see a dangling pointer.
+    //
+    // The compacted file is written under a temporary name and only installed after a
+    // manifest listing the files it replaces has been persisted, see `recover_compaction`.
+    pub fn compaction(&self, index: &RwLock<Index>) -> Result<()> {
+        let (stop_f_id, compaction_f_id) = {
+            let mut writer = self.writer.lock().unwrap();
+            let active = writer.as_mut().ok_or(KvError::ReadOnly)?;
+            let stop_f_id = active.current_f_id.clone();
+            // reserve the next id for the compacted file, writers move on to the one after
+            let compaction_f_id = stop_f_id.inc();
+            active.current_f_id = compaction_f_id.clone();
+            self.roll(active)?;
+            (stop_f_id, compaction_f_id)
+        };
+
+        // each in a critical section of its own, writers get in between
+        index.write().unwrap().prune_aged_history();
+        self.sweep_expired(index)?;
+        // Everything the copy needs to know about the index is taken in one go, readers keep
+        // going meanwhile. Pointers that change afterwards are caught by the swap.
+        let (selected, live, with_history, evicted) = {
+            let index = index.read().unwrap();
+            let selected = self.select_compaction_files(&index, &stop_f_id);
+            let live = index.entries_in(&selected);
+            let with_history: HashSet<Vec<u8>> = live.keys().filter(|key| index.has_history(key)).cloned().collect();
+            let evicted = index.evicted_in(&selected);
+            (selected, live, with_history, evicted)
+        };
+        if selected.is_empty() {
+            return Ok(());
+        }
+
+        // A tombstone in a selected file may still hide older records of its key in the files
+        // that are kept, it is only safe to drop when no older file survives the compaction.
+        let oldest_kept = self.readers.read().unwrap().keys()
+            .find(|f_id| **f_id <= stop_f_id && !selected.contains(f_id))
+            .cloned();
+
+        let compaction_path = Storage::compaction_path(&compaction_f_id, &self.storage_path);
+        let mut writer = BufferedWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&compaction_path)?
+        )?;
+        writer.write_all(&record::file_header())?;
+        let mut moved = Vec::with_capacity(live.len());
+        let mut expired = Vec::new();
+        let mut hints = Vec::with_capacity(live.len());
+        let mut tombstone_bytes = 0;
+        // tombstones no longer retained that may still have to hide older records
+        let mut tombstones = Vec::new();
+        let now = unix_millis()?;
+        for f_id in &selected {
+            let path = Storage::log_path(f_id, &self.storage_path);
+            // a handle of its own, the shared one is only ever read positionally
+            let log = LogFile::open(&path)?;
+            let file_len = log.len()?;
+            let mut reader = BufferedReaderWithPos::new(log.file, log.format.data_start())?;
+            loop {
+                let pos = reader.pos;
+                // the selected files are complete, batches no longer need to be told apart
+                let (cmd, len) = match record::read_next(&mut reader, file_len.saturating_sub(pos), log.format)? {
+                    Next::Record((cmd, _), len) => (cmd, len),
+                    Next::Eof => break,
+                    Next::Incomplete | Next::Corrupt => {
+                        return Err(KvError::Corruption {file: path, offset: pos});
+                    }
+                };
+                let lp = LogPointer {start_pos: pos, len, f_id: f_id.clone()};
+                let older_kept = oldest_kept.as_ref().is_some_and(|kept| kept < f_id);
+                let retained = live.get(cmd.get_key()).is_some_and(|lps| lps.contains(&lp));
+                let keep = match cmd {
+                    Command::Set {..} => retained || evicted.get(cmd.get_key()) == Some(&lp),
+                    Command::Rm {..} if !retained && older_kept => {
+                        tombstones.push(cmd);
+                        continue;
+                    },
+                    Command::Rm {..} => retained,
+                };
+                if !keep {
+                    continue;
+                }
+                let (cmd, retained) = match cmd {
+                    // a key with retained versions keeps them as they are, expired or not
+                    Command::Set {key, sequencer, expires_at, ..}
+                        if is_expired(expires_at, now) && !with_history.contains(&key) => {
+                        expired.push((key.clone(), lp.clone()));
+                        // like a removed key, an older value kept elsewhere must stay hidden
+                        if !older_kept {
+                            continue;
+                        }
+                        (Command::Rm {key, sequencer}, false)
+                    },
+                    cmd => (cmd, retained),
+                };
+
+                let start_pos = writer.pos;
+                writer.write_all(&record::encode(&cmd, false)?)?;
+                let lp_updated = LogPointer {start_pos, len: writer.pos - start_pos, f_id: compaction_f_id.clone()};
+                hints.push(HintEntry::new(&cmd, &lp_updated));
+                match cmd {
+                    Command::Set {key, ..} => moved.push((key, lp, lp_updated)),
+                    Command::Rm {key, ..} if retained => moved.push((key, lp, lp_updated)),
+                    Command::Rm {..} => tombstone_bytes += lp_updated.len,
+                }
+            }
+        }
+        // only those of keys that were not written again since hide anything, checked for all
+        // of them at once rather than one lookup per record
+        tombstones = {
+            let index = index.read().unwrap();
+            tombstones.into_iter().filter(|cmd| index.get_index(cmd.get_key()).is_none()).collect()
+        };
+        for cmd in tombstones {
+            let start_pos = writer.pos;
+            writer.write_all(&record::encode(&cmd, false)?)?;
+            let lp_updated = LogPointer {start_pos, len: writer.pos - start_pos, f_id: compaction_f_id.clone()};
+            hints.push(HintEntry::new(&cmd, &lp_updated));
+            tombstone_bytes += lp_updated.len;
+        }
+        writer.sync()?;
+        hint::write(&Storage::hint_path(&compaction_f_id, &self.storage_path), writer.pos, &hints)?;
+        crash_point!(self, CompactionStep::Copy);
+
+        let manifest = CompactionManifest {output: compaction_f_id.clone(), obsolete: selected.into_iter().collect()};
+        manifest.write(&self.storage_path)?;
+        crash_point!(self, CompactionStep::Manifest);
+
+        let installed_path = Storage::log_path(&compaction_f_id, &self.storage_path);
+        fs::rename(&compaction_path, &installed_path)?;
+        sync_dir(&self.storage_path)?;
+        self.readers.write().unwrap().insert(compaction_f_id.clone(), Arc::new(LogFile::open(&installed_path)?));
+        crash_point!(self, CompactionStep::Install);
+
+        {
+            let mut index = index.write().unwrap();
+            for (key, lp, lp_updated) in moved {
+                let len = lp_updated.len;
+                // written again since the copy was made, the copy is garbage from the start
+                if !index.replace_pointer(&key, &lp, lp_updated) {
+                    index.add_stale(&compaction_f_id, len);
+                }
+            }
+            for (key, lp) in expired {
+                index.remove_expired(&key, &lp);
+            }
+            index.add_stale(&compaction_f_id, tombstone_bytes);
+            for f_id in &manifest.obsolete {
+                index.forget_file(f_id);
+            }
+        }
 
-        // clean up old files
-        let sorted_f_id_l = Storage::sorted_f_id_list(&self.storage_path)?;
-        for f_id in sorted_f_id_l {
-            // new files, ignore
-            if f_id.gt(&stop_f_id) {
+        // clean up old files, nothing in the index points to them any more
+        let mut readers = self.readers.write().unwrap();
+        for f_id in &manifest.obsolete {
+            readers.remove(f_id);
+            Storage::remove_hint(f_id, &self.storage_path)?;
+            fs::remove_file(Storage::log_path(f_id, &self.storage_path))?;
+            crash_point!(self, CompactionStep::DeleteOne);
+        }
+        crash_point!(self, CompactionStep::Delete);
+
+        CompactionManifest::remove(&self.storage_path)?;
+        Ok(())
+    }
+
+    // Runs compaction but stops right after `step`, leaving the directory exactly as a process
+    // killed at that point would. Only meant for crash tests.
+    #[cfg(any(test, feature = "failpoints"))]
+    #[doc(hidden)]
+    pub fn compaction_until(&self, index: &RwLock<Index>, step: CompactionStep) -> Result<()> {
+        *self.crash_after.lock().unwrap() = Some(step);
+        let result = self.compaction(index);
+        *self.crash_after.lock().unwrap() = None;
+        result
+    }
+
+    // Picks the immutable files up to `stop_f_id` worth rewriting, worst stale ratio first.
+    //
+    // Every file at or above the configured stale ratio is taken, plus files that hold
+    // nothing live at all. If there are still more files than allowed, the next worst
+    // ones are merged in until the count after compaction fits.
+    fn select_compaction_files(&self, index: &Index, stop_f_id: &FileId) -> BTreeSet<FileId> {
+        let readers = self.readers.read().unwrap();
+        let no_stats = FileStats::default();
+        let mut ranked: Vec<(&FileId, &FileStats)> = readers.keys()
+            .filter(|f_id| *f_id <= stop_f_id)
+            .map(|f_id| (f_id, index.file_stats().get(f_id).unwrap_or(&no_stats)))
+            .collect();
+        ranked.sort_by(|(_, a), (_, b)| b.stale_ratio().partial_cmp(&a.stale_ratio()).unwrap());
+
+        let mut selected = BTreeSet::new();
+        let mut rest = Vec::new();
+        for (f_id, stats) in ranked {
+            if stats.live_bytes == 0
+                || (stats.stale_bytes > 0 && stats.stale_ratio() >= self.options.compaction_stale_ratio) {
+                selected.insert(f_id.clone());
+            } else {
+                rest.push(f_id);
+            }
+        }
+
+        // the compacted file replaces all selected ones
+        let mut rest = rest.into_iter();
+        while readers.len() + 1 > self.options.compaction_file_count + selected.len() {
+            match rest.next() {
+                Some(f_id) => selected.insert(f_id.clone()),
+                None => break,
+            };
+        }
+        // rewriting a single file without garbage only moves it
+        if selected.len() == 1 {
+            let f_id = selected.iter().next().unwrap();
+            if index.file_stats().get(f_id).is_some_and(|stats| stats.stale_bytes == 0 && stats.live_bytes > 0) {
+                selected.clear();
+            }
+        }
+
+        selected
+    }
+
+    // Rewrites every log file of an older format in the current one and returns how many
+    // files that took.
+    //
+    // Files are rewritten record for record under a temporary name and renamed over the
+    // original one at a time, so an interrupted upgrade leaves a mix of old and new files
+    // that is simply upgraded again.
+    pub fn upgrade(path: &Path, options: &KvStoreOptions) -> Result<usize> {
+        if options.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        let storage_path = path.join(&options.data_dir);
+        if !storage_path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("no store found at {:?}", storage_path)).into());
+        }
+        Storage::recover_compaction(&storage_path)?;
+
+        let mut upgraded = 0;
+        for f_id in Storage::sorted_f_id_list(&storage_path)? {
+            let path = Storage::log_path(&f_id, &storage_path);
+            let file = File::open(&path)?;
+            let format = LogFile::read_format(&file, &path)?;
+            if format == Format::Binary {
                 continue;
             }
 
-            fs::remove_file(Storage::log_path(&f_id, &self.storage_path))?;
+            let upgrade_path = storage_path.join(format!("{}.{}", f_id, UPGRADE_EXT));
+            let mut writer = BufWriter::new(File::create(&upgrade_path)?);
+            writer.write_all(&record::file_header())?;
+            for cmd in Storage::read_legacy(file, format, &path)? {
+                writer.write_all(&record::encode(&cmd, false)?)?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+
+            // offsets change, a hint for the old file would point into the middle of records
+            Storage::remove_hint(&f_id, &storage_path)?;
+            fs::rename(&upgrade_path, &path)?;
+            sync_dir(&storage_path)?;
+            upgraded += 1;
+        }
+        if upgraded > 0 {
+            Checkpoint::remove(&storage_path)?;
         }
+
+        Ok(upgraded)
+    }
+
+    // all commands of a file written in one of the formats before the binary one
+    fn read_legacy(file: File, format: Format, path: &Path) -> Result<Vec<Command>> {
+        let file_len = file.metadata()?.len();
+        let mut cmds = Vec::new();
+        if format == Format::Stream {
+            let mut stream = serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter::<LegacyCommand>();
+            loop {
+                let offset = stream.byte_offset() as u64;
+                match stream.next() {
+                    Some(Ok(cmd)) => cmds.push(cmd.into()),
+                    Some(Err(_)) => return Err(KvError::Corruption {file: path.to_owned(), offset}),
+                    None => return Ok(cmds),
+                }
+            }
+        }
+
+        let mut reader = BufferedReaderWithPos::new(file, 0)?;
+        loop {
+            let pos = reader.pos;
+            match record::read_next(&mut reader, file_len - pos, format)? {
+                Next::Record((cmd, _), _) => cmds.push(cmd),
+                Next::Eof => return Ok(cmds),
+                Next::Incomplete | Next::Corrupt => {
+                    return Err(KvError::Corruption {file: path.to_owned(), offset: pos});
+                }
+            }
+        }
+    }
+
+    // Finishes or rolls back a compaction interrupted by a crash.
+    //
+    // Without a manifest the compacted file may be incomplete, the originals are still intact
+    // and it is thrown away. With a manifest the compacted file is complete and durable, so
+    // it is installed and whatever is left of the files it replaces is deleted. Either way only
+    // one copy of every record survives, so replay never sees the same sequencer twice.
+    fn recover_compaction(storage_path: &Path) -> Result<()> {
+        if let Some(manifest) = CompactionManifest::read(storage_path)? {
+            let compaction_path = Storage::compaction_path(&manifest.output, storage_path);
+            if compaction_path.exists() {
+                fs::rename(&compaction_path, Storage::log_path(&manifest.output, storage_path))?;
+            }
+            for f_id in &manifest.obsolete {
+                Storage::remove_hint(f_id, storage_path)?;
+                let path = Storage::log_path(f_id, storage_path);
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            sync_dir(storage_path)?;
+            CompactionManifest::remove(storage_path)?;
+        }
+
+        // leftovers of a compaction that never got its manifest, or of an interrupted upgrade
+        for entry in fs::read_dir(storage_path)? {
+            let path = entry?.path();
+            let orphan_hint = path.extension() == Some(HINT_EXT.as_ref())
+                && !path.with_extension("dat").exists();
+            let leftover = path.extension() == Some(COMPACTION_EXT.as_ref())
+                || path.extension() == Some(UPGRADE_EXT.as_ref());
+            if path.is_file() && (leftover || orphan_hint) {
+                fs::remove_file(path)?;
+            }
+        }
+
         Ok(())
     }
 
     fn sorted_f_id_list(path: &Path) -> Result<Vec<FileId>> {
-        let mut f_id_list: Vec<FileId> = fs::read_dir(&path)?
+        let mut f_id_list: Vec<FileId> = fs::read_dir(path)?
             .flat_map(|res| -> Result<_> { Ok(res?.path()) })
             .filter(|path| path.is_file() && path.extension() == Some("dat".as_ref()))
             .flat_map(|path| {
                 path.file_name()
                     .and_then(OsStr::to_str)
                     .map(|s| s.trim_end_matches(".dat"))
-                    .map(|s| s.parse::<u64>().and_then(|id| Ok(FileId {id})))
+                    .map(|s| s.parse::<u64>().map(|id| FileId {id}))
             })
             .flatten()
             .collect();
@@ -170,36 +900,169 @@ impl Storage {
         path.join(format!("{}.dat", f_id))
     }
 
+    fn hint_path(f_id: &FileId, path: &Path) -> PathBuf {
+        path.join(format!("{}.{}", f_id, HINT_EXT))
+    }
+
+    // hints are only written for compacted files, most files have none
+    fn remove_hint(f_id: &FileId, path: &Path) -> io::Result<()> {
+        match fs::remove_file(Storage::hint_path(f_id, path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn compaction_path(f_id: &FileId, path: &Path) -> PathBuf {
+        path.join(format!("{}.{}", f_id, COMPACTION_EXT))
+    }
+
     fn new_log_file(f_id: &FileId, path: &Path,
-                    readers: &mut BTreeMap<FileId, BufferedReaderWithPos<File>>) -> Result<BufferedWriterWithPos<File>> {
+                    readers: &mut BTreeMap<FileId, Arc<LogFile>>) -> Result<BufferedWriterWithPos<File>> {
         let new_path = Storage::log_path(f_id, path);
 
         let writer = BufferedWriterWithPos::new(
             OpenOptions::new()
                 .create(true)
+                .truncate(false)
                 .read(true)
                 .write(true)
                 .open(&new_path)?
         )?;
 
-        readers.insert(f_id.clone(), BufferedReaderWithPos::new(File::open(&new_path)?)?);
+        let file = File::open(&new_path)?;
+        readers.insert(f_id.clone(), Arc::new(LogFile {file, format: Format::Binary}));
 
         Ok(writer)
     }
 }
 
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if self.options.sync_policy != SyncPolicy::Never {
+            if let Ok(Some(active)) = self.writer.get_mut() {
+                if let Err(e) = Storage::sync_active(active) {
+                    log::error!("failed to sync the active log on close: {}", e);
+                }
+            }
+        }
+    }
+}
+
+const COMPACTION_EXT: &str = "compact";
+const UPGRADE_EXT: &str = "upgrade";
+const MANIFEST_NAME: &str = "COMPACTION";
+
+/// Steps of a compaction after which a crash can be simulated.
+#[cfg(any(test, feature = "failpoints"))]
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStep {
+    // live entries copied to the temporary file
+    Copy,
+    // manifest persisted
+    Manifest,
+    // compacted file renamed into place
+    Install,
+    // first obsolete file deleted
+    DeleteOne,
+    // all obsolete files deleted
+    Delete,
+}
+
+// Records which file a compaction produced and which files it replaces.
+// Its presence means the compacted file is complete and the old files may be deleted.
+#[derive(Serialize, Deserialize, Debug)]
+struct CompactionManifest {
+    output: FileId,
+    obsolete: Vec<FileId>,
+}
+
+impl CompactionManifest {
+    fn write(&self, storage_path: &Path) -> Result<()> {
+        let tmp_path = storage_path.join(format!("{}.tmp", MANIFEST_NAME));
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.sync_all()?;
+
+        // rename is atomic, a torn manifest is never visible
+        fs::rename(&tmp_path, storage_path.join(MANIFEST_NAME))?;
+        sync_dir(storage_path)?;
+        Ok(())
+    }
+
+    fn read(storage_path: &Path) -> Result<Option<CompactionManifest>> {
+        let path = storage_path.join(MANIFEST_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(BufReader::new(File::open(path)?))?))
+    }
+
+    fn remove(storage_path: &Path) -> Result<()> {
+        fs::remove_file(storage_path.join(MANIFEST_NAME))?;
+        sync_dir(storage_path)?;
+        Ok(())
+    }
+}
+
+// Replays one record into the index. Compaction retaining old versions may have carried one
+// into a newer file than a later version of its key; once they are no longer retained it is
+// just garbage.
+fn replay_entry(index: &mut Index, key: &[u8], sequencer: &Sequencer, tombstone: bool,
+                expires_at: Option<u64>, lp: LogPointer) -> Result<()> {
+    match index.update_entry(key, sequencer, tombstone, expires_at, lp.clone()) {
+        Err(KvError::ConflictError) => {
+            index.add_stale(&lp.f_id, lp.len);
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+// makes renames, creations and deletions in `path` durable
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
 struct BufferedReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
 }
 
 impl<R: Read + Seek> BufferedReaderWithPos<R> {
-    pub fn new(mut inner: R) -> io::Result<Self> {
-        inner.seek(SeekFrom::Start(0))?;
+    pub fn new(mut inner: R, pos: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(pos))?;
 
         Ok(BufferedReaderWithPos {
             reader: BufReader::new(inner),
-            pos: 0,
+            pos,
         })
     }
 }
@@ -238,6 +1101,13 @@ impl<W: Write + Seek> BufferedWriterWithPos<W> {
     }
 }
 
+impl BufferedWriterWithPos<File> {
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
 impl<W: Write + Seek> Write for BufferedWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -259,7 +1129,7 @@ impl<W: Write + Seek> Seek for BufferedWriterWithPos<W> {
     }
 }
 
-#[derive(Eq, PartialEq, PartialOrd, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct FileId {
     pub id: u64
 }
@@ -277,9 +1147,134 @@ impl Display for FileId {
         write!(f, "{:08}", self.id)
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KvStore;
+    use std::thread;
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    // Simulates a process dying at `step` of a compaction and checks that reopening
+    // recovers every value and leaves no compaction leftovers behind.
+    fn crash_compaction_at(step: CompactionStep) -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let value = |iter: usize| format!("{}-{}", iter, "x".repeat(100));
+
+        let store = KvStore::open(temp_dir.path())?;
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), value(iter))?;
+            }
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let storage = Storage::new(temp_dir.path())?;
+        let index = RwLock::new(Index::new());
+        storage.build_index(&mut index.write().unwrap())?;
+        storage.compaction_until(&index, step)?;
+
+        // the crash has to leave something to recover from
+        match CompactionManifest::read(&storage.storage_path)? {
+            Some(manifest) => {
+                assert!(manifest.obsolete.len() > 1);
+                let deleted = manifest.obsolete.iter()
+                    .filter(|f_id| !Storage::log_path(f_id, &storage.storage_path).exists())
+                    .count();
+                let expected = match step {
+                    CompactionStep::DeleteOne => 1,
+                    CompactionStep::Delete => manifest.obsolete.len(),
+                    _ => 0,
+                };
+                assert_eq!(deleted, expected);
+            }
+            None => assert_eq!(step, CompactionStep::Copy),
+        }
+        drop(storage);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0")?, None);
+        for key_id in 1..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(2)));
+        }
+
+        let leftovers = WalkDir::new(temp_dir.path().join("data")).into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.ends_with(".compact") || name.starts_with("COMPACTION")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_crash_after_copy() -> Result<()> {
+        crash_compaction_at(CompactionStep::Copy)
+    }
+
+    #[test]
+    fn compaction_crash_after_manifest() -> Result<()> {
+        crash_compaction_at(CompactionStep::Manifest)
+    }
+
+    #[test]
+    fn compaction_crash_after_install() -> Result<()> {
+        crash_compaction_at(CompactionStep::Install)
+    }
+
+    #[test]
+    fn compaction_crash_mid_delete() -> Result<()> {
+        crash_compaction_at(CompactionStep::DeleteOne)
+    }
 
-impl Ord for FileId {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.id.cmp(&other.id)
+    #[test]
+    fn compaction_crash_after_delete() -> Result<()> {
+        crash_compaction_at(CompactionStep::Delete)
     }
-}
\ No newline at end of file
+
+    // Keys overwritten while compaction copies them leave the copies behind as garbage, every
+    // byte of every file is still accounted for once it is done.
+    #[test]
+    fn overwrites_during_compaction_keep_accounting() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().max_file_size(4096).compaction_file_count(2);
+        let storage = Arc::new(Storage::with_options(temp_dir.path(), &options)?);
+        let index = Arc::new(RwLock::new(Index::new()));
+
+        let writer = {
+            let storage = storage.clone();
+            let index = index.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..200 {
+                    for key_id in 0..20 {
+                        let cmd = Command::Set {
+                            key: format!("key{}", key_id).into_bytes(),
+                            value: format!("{}-{}", iter, "x".repeat(50)).into_bytes(),
+                            sequencer: Sequencer::new()?,
+                            expires_at: None,
+                        };
+                        storage.mutate_batch(&[vec![cmd.clone()]], |mut lps| {
+                            index.write().unwrap().update_index(&cmd, lps.remove(0))
+                        })?;
+                    }
+                }
+                Ok(())
+            })
+        };
+        while !writer.is_finished() {
+            storage.compaction(&index)?;
+        }
+        writer.join().unwrap()?;
+
+        let index = index.read().unwrap();
+        for (f_id, log) in storage.readers.read().unwrap().iter() {
+            let stats = index.file_stats().get(f_id).cloned().unwrap_or_default();
+            assert_eq!(stats.live_bytes + stats.stale_bytes, log.len()?.saturating_sub(record::FILE_HEADER_LEN), "{}", f_id);
+        }
+        Ok(())
+    }
+}
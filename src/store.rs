@@ -1,74 +1,523 @@
 use std::path::PathBuf;
-use crate::{Result, KvError};
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{Result, KvError, KvsEngine, KvStoreOptions, Codec, JsonCodec, Scan};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
 use failure::_core::cmp::Ordering;
 use crate::storage::Storage;
-use crate::Index;
+use crate::{Index, FileId, FileStats};
+use crate::compactor::Compactor;
+use crate::batch::{WriteBatch, WriteOp};
+use crate::transaction::Transaction;
+use crate::scan::prefix_range;
+use crate::index::is_expired;
+use crate::commit::GroupCommit;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::ops::RangeBounds;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 
+/// A handle to a log-structured key/value store.
+///
+/// Handles are cheap to clone and can be shared between threads: reads run concurrently,
+/// while concurrent writes are group committed, so the log and the index are updated in
+/// the same order and many writers share one append and one fsync.
+#[derive(Clone)]
 pub struct KvStore {
-    path: PathBuf,
-    storage: Storage,
-    index: Index,
+    storage: Arc<Storage>,
+    index: Arc<RwLock<Index>>,
+    committer: Arc<GroupCommit<WriteBatch>>,
+    compactor: Arc<Compactor>,
 }
 
 impl KvStore {
 
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let storage_path = path.into();
-        let mut storage = Storage::new(&storage_path)?;
+        let storage = Storage::with_options(&storage_path, &options)?;
 
         let mut index = Index::new();
         storage.build_index(&mut index)?;
 
+        let storage = Arc::new(storage);
+        let index = Arc::new(RwLock::new(index));
+        let compactor = Compactor::start(storage.clone(), index.clone());
+
         Ok(KvStore {
-            path: storage_path.clone(),
             storage,
-            index
+            index,
+            committer: Arc::new(GroupCommit::new()),
+            compactor: Arc::new(compactor),
         })
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {key, value, sequencer: Sequencer::new()?};
+    /// Rewrites a store written by an older version in the current on-disk format.
+    ///
+    /// Returns how many log files had to be rewritten. The store must not be open meanwhile.
+    pub fn upgrade(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<usize> {
+        Storage::upgrade(&path.into(), &options)
+    }
+
+    /// Live and stale bytes per log file, the numbers compaction decisions are based on.
+    pub fn file_stats(&self) -> BTreeMap<FileId, FileStats> {
+        self.index.read().unwrap().file_stats().clone()
+    }
+
+    /// Bytes written since the log was last synced to stable storage.
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.storage.unsynced_bytes()
+    }
+
+    /// Saves the index now, so reopening the store only replays writes made after this call.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.storage.checkpoint(&self.index)
+    }
+
+    /// Sets the value of a string key, overwriting any previous value.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value)
+    }
+
+    /// Gets the value of a string key, returning `None` if the key does not exist.
+    ///
+    /// The key is only borrowed, so a `&str` can be looked up without allocating.
+    pub fn get<K: AsRef<str>>(&self, key: K) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_ref())?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Removes a key, returning `KvError::KeyNotFound` if the key does not exist.
+    pub fn remove<K: Into<String>>(&self, key: K) -> Result<()> {
+        self.remove_bytes(key.into())
+    }
+
+    /// Sets `key` to `value`, both stored as raw bytes.
+    pub fn set_bytes(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.submit(batch)
+    }
+
+    /// Sets `key` to `value` for `ttl`, after which the key reads as absent.
+    ///
+    /// Expired keys take up space until compaction drops them.
+    pub fn set_with_ttl(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set_with_ttl(key, value, ttl);
+        self.submit(batch)
+    }
+
+    /// Makes `key` expire `ttl` from now, failing with `KvError::KeyNotFound` if it is not set.
+    ///
+    /// The value is written again with the new deadline.
+    pub fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let key = key.into();
+        let mut value = Vec::new();
+        loop {
+            let seq = self.get_versioned(&key, &mut value)?.ok_or(KvError::KeyNotFound)?;
+            let mut batch = WriteBatch::new();
+            batch.set_with_ttl(key.clone(), value.clone(), ttl);
+            batch.conditions.push((key.clone(), Some(seq)));
+            match self.submit(batch) {
+                Err(KvError::ConditionFailed) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Time left until `key` expires, `None` if it never does. Fails with
+    /// `KvError::KeyNotFound` if the key is not set.
+    pub fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        let now = unix_millis()?;
+        let index = self.index.read().unwrap();
+        let (_, _, expires_at) = index.get_live(key.as_ref(), now).ok_or(KvError::KeyNotFound)?;
+        Ok(expires_at.map(|deadline| Duration::from_millis(deadline - now)))
+    }
+
+    /// The raw value of `key`, `None` if the key is not set.
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        Ok(if self.get_into(key, &mut value)? { Some(value) } else { None })
+    }
+
+    /// Reads the raw value of `key` into `buf`, replacing its contents and reusing its allocation.
+    /// Returns `false` and leaves `buf` empty if the key is not set.
+    pub fn get_into(&self, key: impl AsRef<[u8]>, buf: &mut Vec<u8>) -> Result<bool> {
+        Ok(self.get_versioned(key.as_ref(), buf)?.is_some())
+    }
+
+    // Like `get_into`, returning the sequencer of the write that set the value.
+    //
+    // The index is only held for the lookup, a slow read must not hold off writers. If
+    // compaction deleted the file before it is read, the key is looked up again.
+    pub(crate) fn get_versioned(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<Sequencer>> {
+        let now = unix_millis()?;
+        loop {
+            buf.clear();
+            let (lp, seq) = match self.index.read().unwrap().get_live(key, now) {
+                Some((lp, seq, _)) => (lp.clone(), seq.clone()),
+                None => return Ok(None),
+            };
+            match self.storage.try_read_value(&lp, buf)? {
+                Some(true) => return Ok(Some(seq)),
+                Some(false) => {
+                    buf.clear();
+                    return Err(KvError::KeyNotFound);
+                },
+                None => continue,
+            }
+        }
+    }
+
+    /// The retained versions of `key`, newest first, each with the sequencer of its write and
+    /// its value, `None` where the key was removed. Expiry does not hide any of them.
+    ///
+    /// Only the current version is kept unless the store was opened with
+    /// `KvStoreOptions::retain_versions` or `KvStoreOptions::retain_for`.
+    pub fn history(&self, key: impl AsRef<str>) -> Result<Vec<(Sequencer, Option<String>)>> {
+        self.history_bytes(key.as_ref())?.into_iter()
+            .map(|(sequencer, value)| Ok((sequencer, value.map(String::from_utf8).transpose()?)))
+            .collect()
+    }
 
-        let log_pointer = self.storage.mutate(cmd.clone())?;
-        self.index.update_index(&cmd, log_pointer)?;
+    /// Same as `history`, with raw values.
+    pub fn history_bytes(&self, key: impl AsRef<[u8]>) -> Result<Vec<(Sequencer, Option<Vec<u8>>)>> {
+        // read like `get_versioned`, starting over if compaction moved any of the versions
+        'lookup: loop {
+            let versions = self.index.read().unwrap().versions_of(key.as_ref());
+            let mut history = Vec::with_capacity(versions.len());
+            for version in versions {
+                let mut value = Vec::new();
+                match self.storage.try_read_value(&version.lp, &mut value)? {
+                    Some(set) => history.push((version.sequencer, if set { Some(value) } else { None })),
+                    None => continue 'lookup,
+                }
+            }
+            return Ok(history);
+        }
+    }
+
+    /// The value `key` had as of `sequencer`, that is after every write up to it. A sequencer
+    /// from `Sequencer::new` stands for the moment it was taken.
+    ///
+    /// `None` if the key was not set then, or the version it had is no longer retained.
+    pub fn get_at(&self, key: impl AsRef<str>, sequencer: &Sequencer) -> Result<Option<String>> {
+        Ok(self.get_at_bytes(key.as_ref(), sequencer)?.map(String::from_utf8).transpose()?)
+    }
 
-        if self.storage.should_compaction() {
-            self.storage.compaction(&mut self.index)?;
+    /// Same as `get_at`, with a raw value.
+    pub fn get_at_bytes(&self, key: impl AsRef<[u8]>, sequencer: &Sequencer) -> Result<Option<Vec<u8>>> {
+        let at_millis = (sequencer.timestamp() / 1_000_000) as u64;
+        // read like `get_versioned`
+        loop {
+            let version = self.index.read().unwrap().versions_of(key.as_ref()).into_iter()
+                .find(|version| version.sequencer.le(sequencer))
+                .filter(|version| !version.tombstone && !is_expired(version.expires_at, at_millis));
+            let version = match version {
+                Some(version) => version,
+                None => return Ok(None),
+            };
+            let mut value = Vec::new();
+            match self.storage.try_read_value(&version.lp, &mut value)? {
+                Some(true) => return Ok(Some(value)),
+                Some(false) => return Ok(None),
+                None => continue,
+            }
         }
-        Ok(())
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(lp) = self.index.get_index(&key) {
-            let cmd = self.storage.get(&lp)?;
-            match cmd.get_value() {
-                Some(v) => Ok(Some(v.clone())),
-                None => Err(KvError::KeyNotFound),
+    /// Sets `key` to `new` if its value is `expected`, atomically with respect to other writers.
+    ///
+    /// `None` stands for the key being absent, as expected value it requires the key not to
+    /// exist and as new value it removes the key.
+    pub fn compare_and_swap(&self, key: impl Into<String>, expected: Option<String>, new: Option<String>) -> Result<CasOutcome> {
+        let key = key.into().into_bytes();
+        let mut current = Vec::new();
+        loop {
+            let seq = self.get_versioned(&key, &mut current)?;
+            if seq.as_ref().map(|_| current.as_slice()) != expected.as_ref().map(|v| v.as_bytes()) {
+                let current = seq.map(|_| String::from_utf8(current)).transpose()?;
+                return Ok(CasOutcome::Mismatch(current));
             }
-        } else {
-            Ok(None)
+
+            let mut batch = WriteBatch::new();
+            match &new {
+                Some(value) => { batch.set(key.clone(), value.clone()); },
+                None if seq.is_some() => { batch.remove(key.clone()); },
+                None => {},
+            }
+            batch.conditions.push((key.clone(), seq));
+            match self.submit(batch) {
+                // changed since it was read, compare again against the new value
+                Err(KvError::ConditionFailed) => continue,
+                result => return result.map(|_| CasOutcome::Swapped),
+            }
+        }
+    }
+
+    /// Sets `key` to `value` unless it is set already.
+    pub fn set_if_absent(&self, key: impl Into<String>, value: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Removes `key` if its value is `expected`.
+    pub fn remove_if_equals(&self, key: impl Into<String>, expected: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Runs `f` as an optimistic transaction and commits its writes atomically.
+    ///
+    /// Nothing is locked while `f` runs. The commit fails with `KvError::ConditionFailed`,
+    /// writing nothing, if any key `f` read was written by someone else in the meantime.
+    /// If `f` fails, nothing is written either. See `transaction_with_retries` to run `f`
+    /// again on conflicts.
+    pub fn transaction<T, F>(&self, mut f: F) -> Result<T>
+        where F: FnMut(&mut Transaction) -> Result<T> {
+        let mut txn = Transaction::new(self);
+        let value = f(&mut txn)?;
+        let batch = txn.into_batch();
+        if !batch.is_empty() || !batch.conditions.is_empty() {
+            self.submit(batch)?;
+        }
+        Ok(value)
+    }
+
+    /// Like `transaction`, running `f` again from scratch on a conflict, up to `attempts` times
+    /// in total.
+    pub fn transaction_with_retries<T, F>(&self, attempts: usize, mut f: F) -> Result<T>
+        where F: FnMut(&mut Transaction) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match self.transaction(&mut f) {
+                Err(KvError::ConditionFailed) if attempt < attempts => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Iterates over the entries with keys in `range`, in key order.
+    ///
+    /// The scan runs backwards with `rev()` and can be capped with `Scan::limit`.
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Scan<(String, String)> {
+        Scan::new(self.storage.clone(), self.index.clone(), range, string_entry)
+    }
+
+    /// Like `scan`, with raw keys and values.
+    pub fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Scan<(Vec<u8>, Vec<u8>)> {
+        Scan::new(self.storage.clone(), self.index.clone(), range, |key, value| Ok((key, value)))
+    }
+
+    /// Iterates over the entries with keys starting with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: impl AsRef<str>) -> Scan<(String, String)> {
+        let range = prefix_range(prefix.as_ref().as_bytes());
+        Scan::new(self.storage.clone(), self.index.clone(), range, string_entry)
+    }
+
+    /// The keys starting with `prefix` in order, without reading their values.
+    pub fn keys_with_prefix(&self, prefix: impl AsRef<str>) -> Scan<String> {
+        let range = prefix_range(prefix.as_ref().as_bytes());
+        Scan::new(self.storage.clone(), self.index.clone(), range, |key, _| Ok(String::from_utf8(key)?)).keys_only()
+    }
+
+    /// Sets `key` to `value` encoded as JSON.
+    pub fn set_typed<T: Serialize + ?Sized>(&self, key: impl Into<Vec<u8>>, value: &T) -> Result<()> {
+        self.set_with_codec::<JsonCodec, T>(key, value)
+    }
+
+    /// The value of `key` decoded from JSON, failing with `KvError::Decode` if it does not hold a `T`.
+    pub fn get_typed<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<Option<T>> {
+        self.get_with_codec::<JsonCodec, T>(key)
+    }
+
+    /// Like `set_typed`, encoding the value with codec `C`.
+    pub fn set_with_codec<C: Codec, T: Serialize + ?Sized>(&self, key: impl Into<Vec<u8>>, value: &T) -> Result<()> {
+        self.set_bytes(key, C::encode(value)?)
+    }
+
+    /// Like `get_typed`, decoding the value with codec `C`.
+    pub fn get_with_codec<C: Codec, T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<Option<T>> {
+        self.get_bytes(key)?.map(|value| C::decode(&value)).transpose()
+    }
+
+    /// Removes `key`, failing with `KvError::KeyNotFound` if it is not set.
+    pub fn remove_bytes(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.remove(key);
+        self.submit(batch)
+    }
+
+    /// Applies all operations of `batch` as one atomic unit, see `WriteBatch`.
+    ///
+    /// Nothing is written if any operation fails.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
         }
+        self.submit(batch)
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if let Some(_) = self.index.get_index(&key) {
-            let cmd = Command::Rm {key, sequencer: Sequencer::new()?};
-            let log_pointer = self.storage.mutate(cmd.clone())?;
+    fn submit(&self, batch: WriteBatch) -> Result<()> {
+        if self.storage.is_read_only() {
+            return Err(KvError::ReadOnly);
+        }
+        self.committer.commit(batch, |batches| self.apply_group(batches))
+    }
 
-            self.index.update_index(&cmd, log_pointer)?;
+    // Turns a group of queued write batches into commands, appends them in one go and updates
+    // the index. Only one group is applied at a time, so the index cannot change under us
+    // except for compaction moving pointers around.
+    fn apply_group(&self, batches: Vec<WriteBatch>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::with_capacity(batches.len());
+        let mut groups = Vec::with_capacity(batches.len());
+        {
+            let now = unix_millis()?;
+            let index = self.index.read().unwrap();
+            // whether a key written by an earlier batch of this group exists after that write
+            let mut touched: HashMap<Vec<u8>, bool> = HashMap::new();
+            for batch in batches {
+                match KvStore::batch_commands(batch, &index, &mut touched, now) {
+                    Ok(cmds) => {
+                        if !cmds.is_empty() {
+                            groups.push(cmds);
+                        }
+                        results.push(Ok(()));
+                    },
+                    Err(e) => results.push(Err(e)),
+                }
+            }
+        }
+        if groups.is_empty() {
+            return Ok(results);
+        }
 
-            if self.storage.should_compaction() {
-                self.storage.compaction(&mut self.index)?;
+        let should_compaction = self.storage.mutate_batch(&groups, |log_pointers| {
+            let mut index = self.index.write().unwrap();
+            for (cmd, log_pointer) in groups.iter().flatten().zip(log_pointers) {
+                index.update_index(cmd, log_pointer)?;
             }
-            Ok(())
-        } else {
-            Err(KvError::KeyNotFound)
+            Ok(self.storage.should_compaction(&index))
+        })?;
+
+        if should_compaction {
+            self.compactor.trigger();
         }
+        if self.storage.should_checkpoint() {
+            self.compactor.checkpoint();
+        }
+        Ok(results)
+    }
+
+    // The commands `batch` turns into, or the error that fails it. `touched` only takes
+    // the writes of a batch that succeeds.
+    fn batch_commands(batch: WriteBatch, index: &Index, touched: &mut HashMap<Vec<u8>, bool>, now: u64)
+        -> Result<Vec<Command>> {
+        for (key, seq) in &batch.conditions {
+            // a batch earlier in the group changes the key, whatever its version is now
+            if touched.contains_key(key) || index.get_live(key, now).map(|(_, seq, _)| seq) != seq.as_ref() {
+                return Err(KvError::ConditionFailed);
+            }
+        }
+
+        let mut own: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut cmds = Vec::with_capacity(batch.len());
+        for op in batch.ops {
+            match op {
+                WriteOp::Set {key, value, ttl} => {
+                    own.insert(key.clone(), true);
+                    let expires_at = match ttl {
+                        Some(ttl) => Some(u64::try_from(ttl.as_millis()).ok()
+                            .and_then(|ttl| now.checked_add(ttl))
+                            .ok_or(KvError::InvalidArgument)?),
+                        None => None,
+                    };
+                    cmds.push(Command::Set {key, value, sequencer: Sequencer::new()?, expires_at});
+                },
+                WriteOp::Remove {key} => {
+                    let exists = own.get(&key).or_else(|| touched.get(&key)).cloned()
+                        .unwrap_or_else(|| index.get_live(&key, now).is_some());
+                    if !exists {
+                        return Err(KvError::KeyNotFound);
+                    }
+                    own.insert(key.clone(), false);
+                    cmds.push(Command::Rm {key, sequencer: Sequencer::new()?});
+                }
+            }
+        }
+        touched.extend(own);
+        Ok(cmds)
+    }
+}
+
+/// Outcome of a conditional write such as `KvStore::compare_and_swap`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CasOutcome {
+    /// The value was as expected and has been replaced.
+    Swapped,
+    /// The value was not as expected, nothing was written. Holds the current value.
+    Mismatch(Option<String>),
+}
+
+impl CasOutcome {
+    pub fn is_swapped(&self) -> bool {
+        *self == CasOutcome::Swapped
+    }
+}
+
+fn string_entry(key: Vec<u8>, value: Vec<u8>) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+impl KvsEngine for KvStore {
+    type Scan = Scan<(String, String)>;
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get<K: AsRef<str>>(&self, key: K) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove<K: Into<String>>(&self, key: K) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        KvStore::set_with_ttl(self, key, value, ttl)
+    }
+
+    fn ttl<K: AsRef<str>>(&self, key: K) -> Result<Option<Duration>> {
+        KvStore::ttl(self, key.as_ref())
+    }
+
+    fn compare_and_swap<K: Into<String>>(&self, key: K, expected: Option<String>, new: Option<String>) -> Result<CasOutcome> {
+        KvStore::compare_and_swap(self, key, expected, new)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        KvStore::write(self, batch)
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Self::Scan {
+        KvStore::scan(self, range)
     }
+
+    fn scan_prefix<K: AsRef<str>>(&self, prefix: K) -> Self::Scan {
+        KvStore::scan_prefix(self, prefix)
+    }
+}
+
+// wall clock time as expiry deadlines are kept
+pub(crate) fn unix_millis() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
 }
 
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub struct Sequencer {
     timestamp: u128
@@ -78,12 +527,32 @@ impl Sequencer {
     // how should I handle this time error = =
     // further more, what should I return if I got this. 500?
     pub fn new() -> Result<Sequencer> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        // two writes in the same clock tick must still get distinct, increasing sequencers
+        let prev = LAST_TIMESTAMP
+            .fetch_update(AtomicOrdering::SeqCst, AtomicOrdering::SeqCst,
+                          |last| Some(now.max(last + 1)))
+            .unwrap_or_else(|last| last);
+
         Ok(
             Sequencer {
-                timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
+                timestamp: now.max(prev + 1) as u128
             }
         )
     }
+
+    // sequencers handed out from now on are newer than `seen`
+    pub(crate) fn observe(seen: &Sequencer) {
+        LAST_TIMESTAMP.fetch_max(seen.timestamp as u64, AtomicOrdering::SeqCst);
+    }
+
+    pub(crate) fn from_timestamp(timestamp: u128) -> Sequencer {
+        Sequencer {timestamp}
+    }
+
+    pub(crate) fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
 }
 
 impl PartialOrd for Sequencer {
@@ -94,29 +563,37 @@ impl PartialOrd for Sequencer {
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub enum Command {
-    Set{key: String, value: String, sequencer: Sequencer},
-    Rm{key: String, sequencer: Sequencer},
+    // `expires_at` is in milliseconds since the Unix epoch
+    Set{key: Vec<u8>, value: Vec<u8>, sequencer: Sequencer, expires_at: Option<u64>},
+    Rm{key: Vec<u8>, sequencer: Sequencer},
 }
 
 impl Command {
-    pub fn get_key(&self) -> &String {
+    pub fn get_key(&self) -> &[u8] {
         match self {
             Command::Set {key,..} => key,
             Command::Rm {key,..} => key
         }
     }
 
-    pub fn get_value(&self) -> Option<&String> {
+    pub fn get_value(&self) -> Option<&[u8]> {
         match self {
-            Command::Set {key:_, value: v, sequencer: _} => Some(v),
+            Command::Set {value: v, ..} => Some(v),
             Command::Rm {..} => None
         }
     }
 
     pub fn get_sequencer(&self) -> &Sequencer {
         match self {
-            Command::Set {key: _k, value: _v, sequencer: seq} => seq,
-            Command::Rm {key: _k, sequencer: seq} => seq,
+            Command::Set {sequencer: seq, ..} => seq,
+            Command::Rm {sequencer: seq, ..} => seq,
+        }
+    }
+
+    pub fn get_expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set {expires_at, ..} => *expires_at,
+            Command::Rm {..} => None,
         }
     }
 }
\ No newline at end of file
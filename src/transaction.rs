@@ -0,0 +1,71 @@
+use crate::{KvError, KvStore, Result, Sequencer, WriteBatch};
+use std::collections::HashMap;
+
+/// The reads and writes of a transaction run by `KvStore::transaction`.
+///
+/// Reads see the store as it is when they happen plus the transaction's own writes, which
+/// are buffered until the commit. Every key read from the store is remembered with its
+/// version, to be validated when the transaction commits.
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    // versions of the keys read from the store, `None` for keys that were not set
+    reads: HashMap<Vec<u8>, Option<Sequencer>>,
+    // values written so far, `None` for removed keys
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(store: &'a KvStore) -> Transaction<'a> {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+            batch: WriteBatch::new(),
+        }
+    }
+
+    /// Gets the value of a string key, see `KvsEngine::get`.
+    pub fn get(&mut self, key: impl AsRef<str>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_ref())?.map(String::from_utf8).transpose()?)
+    }
+
+    /// The raw value of `key`, `None` if the key is not set.
+    pub fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+
+        let mut value = Vec::new();
+        let seq = self.store.get_versioned(key, &mut value)?;
+        // a key read twice keeps the version of the first read, the commit catches the change
+        self.reads.entry(key.to_vec()).or_insert_with(|| seq.clone());
+        Ok(seq.map(|_| value))
+    }
+
+    /// Sets `key` to `value` when the transaction commits.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        let (key, value) = (key.into(), value.into());
+        self.writes.insert(key.clone(), Some(value.clone()));
+        self.batch.set(key, value);
+    }
+
+    /// Removes `key` when the transaction commits, failing with `KvError::KeyNotFound`
+    /// if it is not set.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        if self.get_bytes(&key)?.is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        self.writes.insert(key.clone(), None);
+        self.batch.remove(key);
+        Ok(())
+    }
+
+    pub(crate) fn into_batch(self) -> WriteBatch {
+        let mut batch = self.batch;
+        batch.conditions = self.reads.into_iter().collect();
+        batch
+    }
+}
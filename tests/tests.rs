@@ -1,8 +1,16 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result, Storage, Index, LogPointer, FileId};
+use kvs::{KvStore, KvsEngine, KvError, Result, Storage, Index, LogPointer, FileId};
+use kvs::{KvStoreOptions, SyncPolicy, FileStats, WriteBatch, CasOutcome, Sequencer};
+use std::collections::BTreeMap;
+use predicates::boolean::PredicateBooleanExt;
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::path::Path;
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tempfile::TempDir;
 use walkdir::{WalkDir};
 
@@ -15,9 +23,9 @@ fn cli_no_args() {
 #[test]
 fn storage_set() {
     let temp_dir = TempDir::new().unwrap();
-    let mut storage = Storage::new(&temp_dir.path().to_path_buf()).unwrap();
+    let storage = Storage::new(temp_dir.path()).unwrap();
     let seq = kvs::Sequencer::new().unwrap();
-    let cmd = kvs::Command::Set {key: "key1".to_owned(), value: "value1".to_owned(), sequencer: seq};
+    let cmd = kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: seq, expires_at: None};
     let expected = cmd.clone();
 
     let lp = storage.mutate(cmd).unwrap();
@@ -28,21 +36,21 @@ fn storage_set() {
 #[test]
 fn storage_build_index() {
     let temp_dir = TempDir::new().unwrap();
-    let mut storage = Storage::new(&temp_dir.path().to_path_buf()).unwrap();
+    let storage = Storage::new(temp_dir.path()).unwrap();
     let seq1 = kvs::Sequencer::new().unwrap();
-    let cmd1 = kvs::Command::Set {key: "key1".to_owned(), value: "value1".to_owned(), sequencer: seq1};
+    let cmd1 = kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: seq1, expires_at: None};
     let lp1 = storage.mutate(cmd1).unwrap();
 
     let seq2 = kvs::Sequencer::new().unwrap();
-    let cmd2 = kvs::Command::Set {key: "key2".to_owned(), value: "value2".to_owned(), sequencer: seq2};
+    let cmd2 = kvs::Command::Set {key: "key2".into(), value: "value2".into(), sequencer: seq2, expires_at: None};
     let lp2 = storage.mutate(cmd2).unwrap();
 
     let mut index = Index::new();
 
     storage.build_index(&mut index).expect("FAIL");
 
-    assert_eq!(lp1, index.get_index(&"key1".to_owned()).unwrap());
-    assert_eq!(lp2, index.get_index(&"key2".to_owned()).unwrap());
+    assert_eq!(lp1, index.get_index(b"key1").unwrap());
+    assert_eq!(lp2, index.get_index(b"key2").unwrap());
 }
 
 #[test]
@@ -50,24 +58,24 @@ fn index_iter() {
     let mut index = Index::new();
 
     let seq1 = kvs::Sequencer::new().unwrap();
-    let cmd1 = kvs::Command::Set {key: "key1".to_owned(), value: "value1".to_owned(), sequencer: seq1};
+    let cmd1 = kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: seq1, expires_at: None};
     let lp1 = LogPointer {start_pos: 0, len: 1, f_id: FileId {id: 0}};
     index.update_index(&cmd1, lp1.clone()).expect("FAIL");
 
     let seq2 = kvs::Sequencer::new().unwrap();
-    let cmd2 = kvs::Command::Set {key: "key2".to_owned(), value: "value2".to_owned(), sequencer: seq2};
+    let cmd2 = kvs::Command::Set {key: "key2".into(), value: "value2".into(), sequencer: seq2, expires_at: None};
     let lp2 = LogPointer {start_pos: 1, len: 2, f_id: FileId {id: 0}};
     index.update_index(&cmd2, lp2.clone()).expect("FAIL");
 
     let mut found_key1 = false;
     let mut found_key2 = false;
     for (k, v) in (&mut index).into_iter() {
-        if "key1".eq(k) {
+        if k == b"key1" {
             found_key1 = true;
             assert_eq!(v.0, lp1);
         }
 
-        if "key2".eq(k) {
+        if k == b"key2" {
             found_key2 = true;
             assert_eq!(v.0, lp2);
         }
@@ -80,7 +88,7 @@ fn index_update() {
     let mut index = Index::new();
 
     let seq1 = kvs::Sequencer::new().unwrap();
-    let cmd1 = kvs::Command::Set {key: "key1".to_owned(), value: "value1".to_owned(), sequencer: seq1};
+    let cmd1 = kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: seq1, expires_at: None};
     let lp1 = LogPointer {start_pos: 0, len: 1, f_id: FileId {id: 0}};
     let expected = lp1.clone();
     index.update_index(&cmd1, lp1).expect("FAIL");
@@ -95,18 +103,14 @@ fn index_update_conflict() {
     let seq1 = kvs::Sequencer::new().unwrap();
     let seq2 = kvs::Sequencer::new().unwrap();
 
-    let cmd1 = kvs::Command::Set {key: "key1".to_owned(), value: "value1".to_owned(), sequencer: seq2};
-    let cmd2 = kvs::Command::Set {key: "key1".to_owned(), value: "value2".to_owned(), sequencer: seq1};
+    let cmd1 = kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: seq2, expires_at: None};
+    let cmd2 = kvs::Command::Set {key: "key1".into(), value: "value2".into(), sequencer: seq1, expires_at: None};
     let lp1 = LogPointer {start_pos: 0, len: 1, f_id: FileId {id: 0}};
     let lp2 = LogPointer {start_pos: 2, len: 3, f_id: FileId {id: 0}};
 
     index.update_index(&cmd1, lp1).expect("FAIL");
 
-    // need to find out a way to get rid of this ugly assert
-    match index.update_index(&cmd2, lp2) {
-        Err(_) => {}
-        _ => {assert!(false)}
-    }
+    assert!(matches!(index.update_index(&cmd2, lp2), Err(KvError::ConflictError)));
 }
 
 // `kvs -V` should print the version
@@ -114,18 +118,59 @@ fn index_update_conflict() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs --engine kvs` should be accepted, and unknown engine names rejected.
+#[test]
+fn cli_engine_flag() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "unknown", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// A directory created by another engine should not be opened.
+#[test]
+fn cli_wrong_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("engine"), "other").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 // `kvs get <KEY>` should print "Key not found" for a non-existent key and exit with zero.
 #[test]
 fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -138,7 +183,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -151,7 +196,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -162,14 +207,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -177,7 +222,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -191,13 +236,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -205,7 +250,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -218,13 +263,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -233,19 +278,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -254,13 +299,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -269,16 +314,17 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
 
 // Should get previously stored value.
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -288,7 +334,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -297,9 +343,10 @@ fn get_stored_value() -> Result<()> {
 
 // Should overwrite existent value.
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -308,7 +355,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -318,16 +365,17 @@ fn overwrite_value() -> Result<()> {
 
 // Should get `None` when getting a non-existent key.
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -336,15 +384,16 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -357,7 +406,7 @@ fn remove_key() -> Result<()> {
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     // let p = Path::new("/Users/junwow/myGT/2020/TP201/kvs");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -387,7 +436,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..100 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -397,3 +446,1996 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Clones of a store handle should observe each other's writes.
+#[test]
+fn cloned_handle_shares_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let other = store.clone();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(other.get("key1")?, Some("value1".to_owned()));
+    other.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// Code written against `KvsEngine` alone should reach the extended operations as well.
+fn exercise_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    batch.set("a", "1").set("b", "2").set("c", "3");
+    engine.write(batch)?;
+    engine.remove("b")?;
+    let entries: Vec<(String, String)> = engine.scan("a".to_owned()..).collect::<Result<_>>()?;
+    assert_eq!(entries, vec![("a".to_owned(), "1".to_owned()), ("c".to_owned(), "3".to_owned())]);
+    assert_eq!(engine.scan_prefix("c").count(), 1);
+
+    assert!(engine.compare_and_swap("a", Some("1".to_owned()), Some("4".to_owned()))?.is_swapped());
+    assert_eq!(engine.compare_and_swap("a", Some("1".to_owned()), None)?, CasOutcome::Mismatch(Some("4".to_owned())));
+    assert_eq!(engine.get("a")?, Some("4".to_owned()));
+
+    engine.set_with_ttl("d".to_owned(), "5".to_owned(), Duration::from_secs(60))?;
+    assert!(engine.ttl("d")?.is_some_and(|ttl| ttl <= Duration::from_secs(60)));
+    assert_eq!(engine.ttl("a")?, None);
+    Ok(())
+}
+
+#[test]
+fn engine_extended_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise_engine(KvStore::open(temp_dir.path())?)
+}
+
+// Writers and readers hammering the same keys from many threads.
+// Readers must only ever see values some writer has written, and the final state must
+// reflect the last write of every writer, also after reopening.
+#[test]
+fn concurrent_readers_and_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let writers = 4;
+    let readers = 8;
+    let keys = 50;
+    let rounds = 100;
+
+    let mut handles = Vec::new();
+    for w in 0..writers {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for round in 0..rounds {
+                for k in 0..keys {
+                    // only writer `k % writers` owns key `k`, so its last value is known
+                    if k % writers == w {
+                        store.set(format!("key{}", k), format!("{}-{}", w, round))?;
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for _ in 0..readers {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..rounds {
+                for k in 0..keys {
+                    if let Some(v) = store.get(format!("key{}", k))? {
+                        let owner = v.split('-').next().unwrap();
+                        assert_eq!(owner, format!("{}", k % writers));
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for k in 0..keys {
+            let expected = format!("{}-{}", k % writers, rounds - 1);
+            assert_eq!(store.get(format!("key{}", k))?, Some(expected));
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// Compaction runs in the background while readers keep going; a reader must never be
+// handed a pointer into a file that compaction has already deleted.
+#[test]
+fn reads_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "init".to_owned())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut readers = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        readers.push(thread::spawn(move || -> Result<()> {
+            while !done.load(Ordering::SeqCst) {
+                for key_id in 0..100 {
+                    assert!(store.get(format!("key{}", key_id))?.is_some());
+                }
+                assert_eq!(store.scan_prefix("key").collect::<Result<Vec<_>>>()?.len(), 100);
+            }
+            Ok(())
+        }));
+    }
+
+    // enough overwrites to roll many files and trigger several compactions
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+    Ok(())
+}
+
+// Flips one byte inside the record at `lp`.
+fn corrupt_record(dir: &Path, lp: &LogPointer) {
+    let path = dir.join("data").join(format!("{}.dat", lp.f_id));
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[(lp.start_pos + lp.len - 2) as usize] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+}
+
+// A damaged record should be reported with its location, both on point reads and on replay.
+#[test]
+fn corrupted_record_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = Storage::new(temp_dir.path())?;
+    let mut lps = Vec::new();
+    for key_id in 0..3 {
+        let cmd = kvs::Command::Set {key: format!("key{}", key_id).into(), value: "value".into(), sequencer: kvs::Sequencer::new()?, expires_at: None};
+        lps.push(storage.mutate(cmd)?);
+    }
+
+    corrupt_record(temp_dir.path(), &lps[1]);
+
+    match storage.get(&lps[1]) {
+        Err(KvError::Corruption {offset, ..}) => assert_eq!(offset, lps[1].start_pos),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert_eq!(storage.get(&lps[0])?.get_key(), b"key0");
+    drop(storage);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption {file, offset}) => {
+            assert_eq!(offset, lps[1].start_pos);
+            assert!(file.ends_with(format!("{}.dat", lps[1].f_id)));
+        },
+        Err(e) => panic!("expected corruption, got {:?}", e),
+        Ok(_) => panic!("expected corruption"),
+    }
+    Ok(())
+}
+
+fn newest_data_file(dir: &Path) -> std::path::PathBuf {
+    let mut files: Vec<_> = std::fs::read_dir(dir.join("data")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("dat".as_ref()))
+        .filter(|path| path.metadata().unwrap().len() > 0)
+        .collect();
+    files.sort();
+    files.pop().unwrap()
+}
+
+// A record torn by a crash mid-append at the end of the newest file is dropped on open.
+#[test]
+fn torn_tail_record_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = newest_data_file(temp_dir.path());
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+
+    // the first open repairs the file, the second one must find nothing left to discard
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+    }
+    assert!(std::fs::metadata(&path).unwrap().len() < (bytes.len() - 5) as u64);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// An incomplete record in an older file is not a torn append and must still fail.
+#[test]
+fn truncated_older_file_fails() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = newest_data_file(temp_dir.path());
+    let bytes = std::fs::read(&path).unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    std::fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::Corruption {..})));
+    Ok(())
+}
+
+// Every sync policy should keep data readable across reopens and file rolls.
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::Interval(Duration::from_millis(5)),
+        SyncPolicy::Bytes(4096),
+    ];
+    for policy in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().sync_policy(*policy);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        // enough to roll over to a new file at least once
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), "x".repeat(100))?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..500 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("x".repeat(100)));
+        }
+    }
+    Ok(())
+}
+
+// The last write of a burst is synced in the background once the interval has passed,
+// even though no later write comes along to do it.
+#[test]
+fn interval_sync_without_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().sync_policy(SyncPolicy::Interval(Duration::from_millis(300)));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(store.unsynced_bytes() > 0);
+
+    for _ in 0..20 {
+        thread::sleep(Duration::from_millis(100));
+        if store.unsynced_bytes() == 0 {
+            break;
+        }
+    }
+    assert_eq!(store.unsynced_bytes(), 0);
+    Ok(())
+}
+
+// Removes racing on the same key may end up in one group commit batch;
+// exactly one of them must win.
+#[test]
+fn concurrent_removes_same_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for round in 0..20 {
+        let key = format!("key{}", round);
+        store.set(key.clone(), "value".to_owned())?;
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let store = store.clone();
+            let key = key.clone();
+            thread::spawn(move || store.remove(key))
+        }).collect();
+
+        let mut removed = 0;
+        for handle in handles {
+            match handle.join().unwrap() {
+                Ok(()) => removed += 1,
+                Err(KvError::KeyNotFound) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        assert_eq!(removed, 1);
+        assert_eq!(store.get(key)?, None);
+    }
+    Ok(())
+}
+
+fn data_file_count(dir: &Path) -> usize {
+    std::fs::read_dir(dir).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("dat".as_ref()))
+        .count()
+}
+
+// A small max file size rolls files sooner, a high file count keeps compaction away.
+#[test]
+fn options_file_size_and_compaction_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_file_size(1024)
+        .compaction_file_count(1000)
+        .data_dir("logs");
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    drop(store);
+
+    assert!(!temp_dir.path().join("data").exists());
+    assert!(data_file_count(&temp_dir.path().join("logs")) > 20);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key199")?, Some("x".repeat(100)));
+    Ok(())
+}
+
+#[test]
+fn options_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = KvStoreOptions::default().read_only(true);
+    assert!(KvStore::open_with_options(temp_dir.path(), read_only.clone()).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = data_file_count(&temp_dir.path().join("data"));
+
+    let store = KvStore::open_with_options(temp_dir.path(), read_only)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(matches!(store.set("key2".to_owned(), "value2".to_owned()), Err(KvError::ReadOnly)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvError::ReadOnly)));
+    drop(store);
+
+    // no new active file was created
+    assert_eq!(data_file_count(&temp_dir.path().join("data")), files);
+    Ok(())
+}
+
+#[test]
+fn options_create_if_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let no_create = KvStoreOptions::default().create_if_missing(false);
+    assert!(KvStore::open_with_options(temp_dir.path(), no_create.clone()).is_err());
+
+    KvStore::open(temp_dir.path())?;
+    KvStore::open_with_options(temp_dir.path(), no_create)?;
+    Ok(())
+}
+
+// The store knobs are available on the command line.
+#[test]
+fn cli_store_options() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--no-create", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--max-file-size", "1024", "--compaction-files", "8", "--compaction-stale-ratio", "0.4",
+               "--sync", "every-write",
+               "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--sync", "sometimes", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn index_stale_accounting() {
+    let mut index = Index::new();
+    let f1 = FileId {id: 1};
+    let f2 = FileId {id: 2};
+
+    let cmd1 = kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: kvs::Sequencer::new().unwrap(), expires_at: None};
+    index.update_index(&cmd1, LogPointer {start_pos: 0, len: 10, f_id: f1.clone()}).expect("FAIL");
+    let cmd2 = kvs::Command::Set {key: "key1".into(), value: "value2".into(), sequencer: kvs::Sequencer::new().unwrap(), expires_at: None};
+    index.update_index(&cmd2, LogPointer {start_pos: 10, len: 12, f_id: f1.clone()}).expect("FAIL");
+
+    assert_eq!(index.file_stats()[&f1], FileStats {live_bytes: 12, stale_bytes: 10});
+
+    let cmd3 = kvs::Command::Rm {key: "key1".into(), sequencer: kvs::Sequencer::new().unwrap()};
+    index.update_index(&cmd3, LogPointer {start_pos: 0, len: 5, f_id: f2.clone()}).expect("FAIL");
+
+    assert_eq!(index.file_stats()[&f1], FileStats {live_bytes: 0, stale_bytes: 22});
+    assert_eq!(index.file_stats()[&f2], FileStats {live_bytes: 0, stale_bytes: 5});
+    assert_eq!(index.total_stats().stale_ratio(), 1.0);
+}
+
+// A dataset without overwrites has nothing to reclaim and is never rewritten,
+// however many files it spans.
+#[test]
+fn write_once_never_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_file_size(1024).compaction_file_count(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+
+    let stats = store.file_stats();
+    assert!(stats.values().all(|s| s.stale_bytes == 0));
+    drop(store);
+    // every file written is still there
+    assert!(data_file_count(&temp_dir.path().join("data")) > 20);
+    Ok(())
+}
+
+// With the file count trigger out of the way, the stale ratio alone drives compaction,
+// and reopening rebuilds the same accounting from the log.
+#[test]
+fn stale_ratio_triggers_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_file_size(4096)
+        .compaction_file_count(1000)
+        .compaction_stale_ratio(0.3);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    // compaction runs in the background, give it a moment
+    let mut files = data_file_count(&temp_dir.path().join("data"));
+    for _ in 0..100 {
+        if files < 10 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+        files = data_file_count(&temp_dir.path().join("data"));
+    }
+    assert!(files < 10);
+
+    let live = |stats: BTreeMap<FileId, FileStats>| stats.values().map(|s| s.live_bytes).sum::<u64>();
+    let live_before = live(store.file_stats());
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(live(store.file_stats()), live_before);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// Writes cold keys once, removes one of them and then churns a hot key, all with compaction
+// held off. Returns the options and the ids of the files holding the cold keys.
+fn cold_and_hot_files(dir: &Path) -> Result<(KvStoreOptions, Vec<FileId>)> {
+    let options = KvStoreOptions::default()
+        .max_file_size(1024)
+        .compaction_file_count(1000)
+        .compaction_stale_ratio(1.0);
+    let store = KvStore::open_with_options(dir, options.clone())?;
+    for key_id in 0..20 {
+        store.set(format!("cold{}", key_id), "x".repeat(100))?;
+    }
+    // the hot writes start out in the last file, it is not cold
+    let mut cold_files: Vec<FileId> = store.file_stats().keys().cloned().collect();
+    cold_files.pop();
+    store.remove("cold0".to_owned())?;
+    for iter in 0..100 {
+        store.set("hot".to_owned(), format!("{}-{}", iter, "y".repeat(100)))?;
+    }
+    drop(store);
+    Ok((options.compaction_stale_ratio(0.5), cold_files))
+}
+
+// Only files that are mostly garbage are rewritten, the cold ones are left alone.
+#[test]
+fn selective_compaction_keeps_clean_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, cold_files) = cold_and_hot_files(temp_dir.path())?;
+    let data_dir = temp_dir.path().join("data");
+    let files_before = data_file_count(&data_dir);
+
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    drop(storage);
+
+    assert!(data_file_count(&data_dir) < files_before / 2);
+    for f_id in &cold_files {
+        assert!(data_dir.join(format!("{}.dat", f_id)).exists());
+    }
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let stats = store.file_stats();
+    for f_id in &cold_files {
+        assert!(stats[f_id].stale_ratio() < 0.5);
+    }
+    for key_id in 1..20 {
+        assert_eq!(store.get(format!("cold{}", key_id))?, Some("x".repeat(100)));
+    }
+    assert_eq!(store.get("hot")?, Some(format!("99-{}", "y".repeat(100))));
+    Ok(())
+}
+
+// The tombstone of a removed key lives in a rewritten file while its old value stays behind
+// in a kept one. Compaction has to carry the tombstone over or the key comes back.
+#[test]
+fn selective_compaction_keeps_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, cold_files) = cold_and_hot_files(temp_dir.path())?;
+
+    for _ in 0..2 {
+        let storage = Storage::with_options(temp_dir.path(), &options)?;
+        let index = RwLock::new(Index::new());
+        storage.build_index(&mut index.write().unwrap())?;
+        storage.compaction(&index)?;
+        drop(storage);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert!(store.file_stats().contains_key(&cold_files[0]));
+        assert_eq!(store.get("cold0")?, None);
+        assert_eq!(store.get("cold1")?, Some("x".repeat(100)));
+    }
+
+    // once every file older than the tombstone is rewritten as well, it is dropped
+    let options = options.compaction_file_count(1);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    assert_eq!(index.read().unwrap().total_stats().stale_bytes, 0);
+    drop(storage);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("cold0")?, None);
+    assert_eq!(store.file_stats().len(), 1);
+    Ok(())
+}
+
+// Overwrites every key a few times and compacts everything into one file.
+// Returns the pointer of `key0` in the compacted file.
+fn compact_with_hint(dir: &Path) -> Result<LogPointer> {
+    let store = KvStore::open(dir)?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}-{}", iter, "x".repeat(100)))?;
+        }
+    }
+    store.remove("key99".to_owned())?;
+    drop(store);
+
+    let storage = Storage::with_options(dir, &KvStoreOptions::default().compaction_file_count(1))?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    let lp = index.read().unwrap().get_index(b"key0").unwrap();
+    Ok(lp)
+}
+
+// Opening a compacted store takes the index from the hint file and never reads the values.
+#[test]
+fn hint_file_used_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lp = compact_with_hint(temp_dir.path())?;
+    assert!(temp_dir.path().join("data").join(format!("{}.hint", lp.f_id)).exists());
+
+    // a damaged value would fail a replay, with the hint it only shows up when read
+    corrupt_record(temp_dir.path(), &lp);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.get("key0"), Err(KvError::Corruption {..})));
+    for key_id in 1..99 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("2-{}", "x".repeat(100))));
+    }
+    assert_eq!(store.get("key99")?, None);
+    Ok(())
+}
+
+// A damaged hint file is ignored and the log file is replayed instead.
+#[test]
+fn damaged_hint_file_replayed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lp = compact_with_hint(temp_dir.path())?;
+    let stats_before = KvStore::open(temp_dir.path())?.file_stats();
+
+    let hint_path = temp_dir.path().join("data").join(format!("{}.hint", lp.f_id));
+    let bytes = std::fs::read(&hint_path).unwrap();
+    std::fs::write(&hint_path, &bytes[..bytes.len() / 2]).unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.file_stats(), stats_before);
+    for key_id in 0..99 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("2-{}", "x".repeat(100))));
+    }
+    assert_eq!(store.get("key99")?, None);
+    Ok(())
+}
+
+// Hint files and checkpoints store keys as they are, a large binary key takes about its own
+// size in either.
+#[test]
+fn binary_keys_in_hint_and_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(key.clone(), "value")?;
+    store.checkpoint()?;
+    drop(store);
+    let checkpoint_len = std::fs::metadata(temp_dir.path().join("data").join("INDEX")).unwrap().len();
+    assert!(checkpoint_len < 2 * key.len() as u64);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(b"value".to_vec()));
+    drop(store);
+
+    let storage = Storage::with_options(temp_dir.path(), &KvStoreOptions::default().compaction_file_count(1))?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    let lp = index.read().unwrap().get_index(&key).unwrap();
+    drop(storage);
+    let hint_path = temp_dir.path().join("data").join(format!("{}.hint", lp.f_id));
+    assert!(std::fs::metadata(hint_path).unwrap().len() < 2 * key.len() as u64);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// Reopening after a checkpoint only replays the log written after it: a damaged record the
+// checkpoint covers goes unnoticed until it is read.
+#[test]
+fn checkpoint_replays_only_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = Storage::new(temp_dir.path())?;
+    let cmd = kvs::Command::Set {key: "old".into(), value: "value".into(), sequencer: kvs::Sequencer::new()?, expires_at: None};
+    let lp = storage.mutate(cmd)?;
+    drop(storage);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "before".to_owned())?;
+    }
+    store.checkpoint()?;
+    for key_id in 50..150 {
+        store.set(format!("key{}", key_id), "after".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    let stats = store.file_stats();
+    drop(store);
+
+    corrupt_record(temp_dir.path(), &lp);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.file_stats(), stats);
+    assert!(matches!(store.get("old"), Err(KvError::Corruption {..})));
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?, Some("before".to_owned()));
+    assert_eq!(store.get("key149")?, Some("after".to_owned()));
+    Ok(())
+}
+
+// Checkpoints taken while writers keep going cover exactly the writes indexed before them.
+#[test]
+fn checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let writers: Vec<_> = (0..4).map(|thread_id| {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 0..200 {
+                store.set(format!("key{}-{}", thread_id, iter % 20), format!("{}", iter))?;
+            }
+            Ok(())
+        })
+    }).collect();
+    while writers.iter().any(|writer| !writer.is_finished()) {
+        store.checkpoint()?;
+    }
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    let stats = store.file_stats();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.file_stats(), stats);
+    for thread_id in 0..4 {
+        for key_id in 0..20 {
+            assert_eq!(store.get(format!("key{}-{}", thread_id, key_id))?, Some(format!("{}", 180 + key_id)));
+        }
+    }
+    Ok(())
+}
+
+// A checkpoint pointing into files compaction has since replaced, or a damaged one,
+// is ignored in favour of a full replay.
+#[test]
+fn outdated_checkpoint_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_file_count(1000).compaction_stale_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}-{}", iter, "x".repeat(100)))?;
+        }
+        if iter == 0 {
+            store.checkpoint()?;
+        }
+    }
+    drop(store);
+
+    // compaction outside of a store, nothing refreshes the checkpoint afterwards
+    let storage = Storage::with_options(temp_dir.path(), &options.clone().compaction_file_count(1))?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    drop(storage);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("2-{}", "x".repeat(100))));
+    }
+    store.checkpoint()?;
+    drop(store);
+
+    let checkpoint_path = temp_dir.path().join("data").join("INDEX");
+    let bytes = std::fs::read(&checkpoint_path).unwrap();
+    std::fs::write(&checkpoint_path, &bytes[..bytes.len() - 3]).unwrap();
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("2-{}", "x".repeat(100))));
+    }
+    Ok(())
+}
+
+// The background worker checkpoints on its own once enough has been written.
+#[test]
+fn periodic_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().checkpoint_bytes(4096);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    drop(store);
+    let checkpoint_path = temp_dir.path().join("data").join("INDEX");
+    assert!(!checkpoint_path.exists());
+
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().checkpoint_bytes(1024))?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "y".repeat(100))?;
+    }
+    // dropping the store waits for the worker
+    drop(store);
+    assert!(checkpoint_path.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("y".repeat(100)));
+    }
+    Ok(())
+}
+
+// A command as json, the way it was stored while keys and values were strings.
+fn legacy_json(cmd: &kvs::Command) -> Vec<u8> {
+    let key = String::from_utf8(cmd.get_key().to_vec()).unwrap();
+    let json = match cmd.get_value() {
+        Some(value) => {
+            let value = String::from_utf8(value.to_vec()).unwrap();
+            serde_json::json!({"Set": {"key": key, "value": value, "sequencer": cmd.get_sequencer()}})
+        },
+        None => serde_json::json!({"Rm": {"key": key, "sequencer": cmd.get_sequencer()}}),
+    };
+    serde_json::to_vec(&json).unwrap()
+}
+
+// Frames a command the way logs were written before the binary format, with no file header.
+fn legacy_json_record(cmd: &kvs::Command) -> Vec<u8> {
+    let payload = legacy_json(cmd);
+    let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// Logs written in json by earlier versions stay readable, and compaction rewrites them
+// in the binary format.
+#[test]
+fn legacy_json_log_readable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    std::fs::create_dir(&data_dir).unwrap();
+    let mut log = Vec::new();
+    for (key, value) in &[("key1", "value1"), ("key2", "\"quoted\"")] {
+        let cmd = kvs::Command::Set {key: key.to_string().into(), value: value.to_string().into(), sequencer: kvs::Sequencer::new()?, expires_at: None};
+        log.extend(legacy_json_record(&cmd));
+    }
+    log.extend(legacy_json_record(&kvs::Command::Rm {key: "key1".into(), sequencer: kvs::Sequencer::new()?}));
+    std::fs::write(data_dir.join("00000001.dat"), &log).unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("\"quoted\"".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::default().compaction_file_count(1);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    drop(storage);
+    assert!(!data_dir.join("00000001.dat").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("\"quoted\"".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// New logs start with the format header and hold keys and values as raw bytes.
+#[test]
+fn binary_records_unescaped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "{\"nested\": \"json\"}\n\ttabs and ünïcödé";
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), value.to_owned())?;
+    drop(store);
+
+    let bytes = std::fs::read(newest_data_file(temp_dir.path())).unwrap();
+    assert_eq!(&bytes[..4], b"\x89KVS");
+    assert!(bytes.windows(value.len()).any(|window| window == value.as_bytes()));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some(value.to_owned()));
+    Ok(())
+}
+
+// Keys and values that are not valid utf-8 survive reopening and compaction.
+#[test]
+fn bytes_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x80, 0x81, 0x00, 0xc3];
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(&b"gone\xff"[..], &b"x"[..])?;
+    store.remove_bytes(&b"gone\xff"[..])?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    drop(store);
+
+    let options = KvStoreOptions::default().compaction_file_count(1);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    drop(storage);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    assert_eq!(store.get_bytes(b"gone\xff")?, None);
+    Ok(())
+}
+
+// The string methods share the keyspace with the byte methods, and refuse values that are not text.
+#[test]
+fn string_get_of_binary_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_bytes("key1")?, Some(b"value1".to_vec()));
+
+    store.set_bytes("key2", vec![0xff, 0xfe])?;
+    match store.get("key2") {
+        Err(KvError::Utf8(_)) => {},
+        other => panic!("expected a utf-8 error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Lookups borrow their keys, and `get_into` reuses the caller's buffer.
+#[test]
+fn borrowed_lookups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "a".repeat(100))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("a".repeat(100)));
+    assert_eq!(store.get_bytes(b"key2")?, Some(b"value2".to_vec()));
+
+    let mut buf = Vec::new();
+    assert!(store.get_into("key1", &mut buf)?);
+    assert_eq!(buf, "a".repeat(100).into_bytes());
+    let capacity = buf.capacity();
+    assert!(store.get_into("key2", &mut buf)?);
+    assert_eq!(buf, b"value2");
+    assert_eq!(buf.capacity(), capacity);
+
+    store.remove("key2")?;
+    assert!(!store.get_into("key2", &mut buf)?);
+    assert!(buf.is_empty());
+    assert!(matches!(store.remove("key2"), Err(KvError::KeyNotFound)));
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Session {
+    user: String,
+    expires: u64,
+    roles: Vec<String>,
+}
+
+fn session() -> Session {
+    Session {user: "alice".to_owned(), expires: 1_700_000_000, roles: vec!["admin".to_owned()]}
+}
+
+// Typed values are stored as json, and reading one as the wrong type is a decode error.
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_typed("session", &session())?;
+    store.set_typed("count", &42u32)?;
+    assert_eq!(store.get_typed::<Session>("session")?, Some(session()));
+    assert_eq!(store.get_typed::<u32>("count")?, Some(42));
+    assert_eq!(store.get_typed::<u32>("missing")?, None);
+    assert!(store.get("session")?.unwrap().contains("\"alice\""));
+
+    match store.get_typed::<Session>("count") {
+        Err(KvError::Decode(_)) => {},
+        other => panic!("expected a decode error, got {:?}", other),
+    }
+
+    // json has no map keys other than strings
+    let by_pair: BTreeMap<(u32, u32), u32> = vec![((1, 2), 3)].into_iter().collect();
+    assert!(matches!(store.set_typed("pairs", &by_pair), Err(KvError::Encode(_))));
+    assert_eq!(store.get("pairs")?, None);
+    Ok(())
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode_values() -> Result<()> {
+    use kvs::{BincodeCodec, JsonCodec};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_codec::<BincodeCodec, _>("session", &session())?;
+    store.set_with_codec::<JsonCodec, _>("json", &session())?;
+    assert!(store.get_bytes("session")?.unwrap().len() < store.get_bytes("json")?.unwrap().len());
+    assert_eq!(store.get_with_codec::<BincodeCodec, Session>("session")?, Some(session()));
+    assert!(matches!(store.get_typed::<Session>("session"), Err(KvError::Decode(_))));
+    Ok(())
+}
+
+// Scans return entries in key order from either end, within bounds and up to a limit.
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key5")?;
+    let keys = |scan: Vec<Result<(String, String)>>| -> Result<Vec<String>> {
+        scan.into_iter().map(|entry| entry.map(|(key, _)| key)).collect()
+    };
+
+    let all = store.scan(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 9);
+    assert_eq!(all[0], ("key0".to_owned(), "value0".to_owned()));
+    assert_eq!(keys(store.scan("key3".to_owned().."key7".to_owned()).collect())?, ["key3", "key4", "key6"]);
+    assert_eq!(keys(store.scan("key3".to_owned()..="key7".to_owned()).rev().collect())?, ["key7", "key6", "key4", "key3"]);
+    assert_eq!(keys(store.scan("key2".to_owned()..).limit(2).collect())?, ["key2", "key3"]);
+    assert_eq!(keys(store.scan(.."key9".to_owned()).limit(2).rev().collect())?, ["key8", "key7"]);
+    assert_eq!(store.scan("key7".to_owned().."key3".to_owned()).count(), 0);
+
+    let mut scan = store.scan(..);
+    assert_eq!(scan.next().unwrap()?.0, "key0");
+    assert_eq!(scan.next_back().unwrap()?.0, "key9");
+    assert_eq!(scan.count(), 7);
+
+    store.set_bytes(vec![0xff], vec![0x00])?;
+    let tail = store.scan_bytes(b"key9".to_vec()..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(tail, [(b"key9".to_vec(), b"value9".to_vec()), (vec![0xff], vec![0x00])]);
+    Ok(())
+}
+
+// A scan reads each value when it gets to it, so it sees writes and compaction that happen meanwhile.
+#[test]
+fn scan_reads_lazily() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_file_size(1024).compaction_file_count(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "old".to_owned())?;
+    }
+
+    let mut scan = store.scan(..);
+    assert_eq!(scan.next().unwrap()?, ("key000".to_owned(), "old".to_owned()));
+    store.set("key001".to_owned(), "new".to_owned())?;
+    store.remove("key002")?;
+    for key_id in 3..100 {
+        store.set(format!("key{:03}", key_id), "x".repeat(50))?;
+    }
+
+    assert_eq!(scan.next().unwrap()?, ("key001".to_owned(), "new".to_owned()));
+    assert_eq!(scan.next().unwrap()?, ("key003".to_owned(), "x".repeat(50)));
+    assert_eq!(scan.count(), 96);
+    Ok(())
+}
+
+// Prefix queries return exactly the keys under the prefix, in order.
+#[test]
+fn prefix_queries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:122:profile", "user:123:profile", "user:123:settings", "user:1234:profile", "user:124:profile"] {
+        store.set(key.to_string(), format!("{} value", key))?;
+    }
+
+    let keys = store.keys_with_prefix("user:123:").collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["user:123:profile", "user:123:settings"]);
+    let entries = store.scan_prefix("user:123").limit(2).collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries, [
+        ("user:1234:profile".to_owned(), "user:1234:profile value".to_owned()),
+        ("user:123:profile".to_owned(), "user:123:profile value".to_owned()),
+    ]);
+    assert_eq!(store.keys_with_prefix("").count(), 5);
+    assert_eq!(store.keys_with_prefix("user:125").count(), 0);
+    Ok(())
+}
+
+#[test]
+fn cli_ls_and_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in &[("user:1:name", "ann"), ("user:1:mail", "ann@example.com"), ("user:2:name", "bob"), ("team", "core")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ls"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("team\nuser:1:mail\nuser:1:name\nuser:2:name\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ls", "user:", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1:mail\nuser:1:name\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "user:1:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1:mail\tann@example.com\nuser:1:name\tann\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "user:", "--keys-only", "--limit", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1:mail\n");
+}
+
+// A batch is applied as a whole, and a batch with a failing remove is not applied at all.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("name:anne".to_owned(), "user:1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("user:1", "ann").set("name:ann", "user:1").remove("name:anne");
+    assert_eq!(batch.len(), 3);
+    store.write(batch)?;
+    assert_eq!(store.get("user:1")?, Some("ann".to_owned()));
+    assert_eq!(store.get("name:ann")?, Some("user:1".to_owned()));
+    assert_eq!(store.get("name:anne")?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("user:2", "bob").remove("name:bob");
+    assert!(matches!(store.write(batch), Err(KvError::KeyNotFound)));
+    assert_eq!(store.get("user:2")?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("tmp", "x").remove("tmp");
+    store.write(batch)?;
+    assert_eq!(store.get("tmp")?, None);
+    store.write(WriteBatch::new())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1")?, Some("ann".to_owned()));
+    assert_eq!(store.get("name:anne")?, None);
+    assert_eq!(store.get("tmp")?, None);
+    Ok(())
+}
+
+// Cutting a batch short anywhere drops all of it on replay, with whatever was written before kept.
+#[test]
+fn torn_batch_discarded() -> Result<()> {
+    // record frame, command header, key and value
+    let last_record_len = 8 + 25 + "key3".len() as u64 + "value3".len() as u64;
+    for cut in [1, last_record_len, last_record_len + 1] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key0".to_owned(), "value0".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set("key1", "value1").set("key2", "value2").remove("key0").set("key3", "value3");
+        store.write(batch)?;
+        drop(store);
+
+        let path = newest_data_file(temp_dir.path());
+        let len = path.metadata().unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - cut).unwrap();
+        drop(file);
+
+        let options = KvStoreOptions::default().read_only(true);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1")?, None);
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+        for key in &["key1", "key2", "key3"] {
+            assert_eq!(store.get(key)?, None);
+        }
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+    }
+    Ok(())
+}
+
+// A transaction sees its own writes and commits them together.
+#[test]
+fn transaction_commits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "10".to_owned())?;
+    store.set("to".to_owned(), "5".to_owned())?;
+
+    let moved = store.transaction(|txn| {
+        let from: u32 = txn.get("from")?.unwrap().parse().unwrap();
+        let to: u32 = txn.get("to")?.unwrap().parse().unwrap();
+        txn.set("from", (from - 3).to_string());
+        txn.set("to", (to + 3).to_string());
+        assert_eq!(txn.get("from")?, Some("7".to_owned()));
+        txn.remove("to")?;
+        assert_eq!(txn.get("to")?, None);
+        assert!(matches!(txn.remove("missing"), Err(KvError::KeyNotFound)));
+        txn.set("to", (to + 3).to_string());
+        Ok(3)
+    })?;
+    assert_eq!(moved, 3);
+    assert_eq!(store.get("from")?, Some("7".to_owned()));
+    assert_eq!(store.get("to")?, Some("8".to_owned()));
+
+    // a failing transaction writes nothing
+    let result: Result<()> = store.transaction(|txn| {
+        txn.set("from", "0");
+        Err(KvError::InvalidArgument)
+    });
+    assert!(matches!(result, Err(KvError::InvalidArgument)));
+    assert_eq!(store.get("from")?, Some("7".to_owned()));
+    Ok(())
+}
+
+// A write to a key the transaction read, made between the read and the commit, fails the commit.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let other = store.clone();
+
+    let result = store.transaction(|txn| {
+        txn.get("key1")?;
+        other.set("key1".to_owned(), "changed".to_owned())?;
+        txn.set("key2", "derived");
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvError::ConditionFailed)));
+    assert_eq!(store.get("key2")?, None);
+
+    // reading a key as absent conflicts with it being created
+    let result = store.transaction(|txn| {
+        assert_eq!(txn.get("key3")?, None);
+        other.set("key3".to_owned(), "created".to_owned())?;
+        txn.set("key3", "mine");
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvError::ConditionFailed)));
+    assert_eq!(store.get("key3")?, Some("created".to_owned()));
+
+    // keys only written blindly do not conflict
+    store.transaction(|txn| {
+        other.set("key1".to_owned(), "again".to_owned())?;
+        txn.set("key1", "blind");
+        Ok(())
+    })?;
+    assert_eq!(store.get("key1")?, Some("blind".to_owned()));
+
+    let mut runs = 0;
+    store.transaction_with_retries(3, |txn| {
+        runs += 1;
+        let value = txn.get("key1")?.unwrap();
+        if runs == 1 {
+            other.set("key1".to_owned(), "interleaved".to_owned())?;
+        }
+        txn.set("key1", format!("{}+", value));
+        Ok(())
+    })?;
+    assert_eq!(runs, 2);
+    assert_eq!(store.get("key1")?, Some("interleaved+".to_owned()));
+    Ok(())
+}
+
+// Concurrent read-modify-write transactions never lose an update.
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let conflicts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let handles: Vec<_> = (0..8).map(|_| {
+        let store = store.clone();
+        let conflicts = conflicts.clone();
+        thread::spawn(move || {
+            for _ in 0..25 {
+                store.transaction_with_retries(usize::MAX, |txn| {
+                    let counter: u64 = txn.get("counter")?.unwrap().parse().unwrap();
+                    thread::yield_now();
+                    txn.set("counter", (counter + 1).to_string());
+                    Ok(())
+                }).unwrap();
+            }
+            // the same increment without retries either commits or reports the conflict
+            match store.transaction(|txn| {
+                let counter: u64 = txn.get("counter")?.unwrap().parse().unwrap();
+                txn.set("counter", (counter + 1).to_string());
+                Ok(())
+            }) {
+                Ok(()) => {},
+                Err(KvError::ConditionFailed) => { conflicts.fetch_add(1, Ordering::SeqCst); },
+                Err(e) => panic!("{:?}", e),
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let expected = 8 * 25 + 8 - conflicts.load(Ordering::SeqCst);
+    assert_eq!(store.get("counter")?, Some(expected.to_string()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("lease", "node1".to_owned())?.is_swapped());
+    assert_eq!(store.set_if_absent("lease", "node2".to_owned())?, CasOutcome::Mismatch(Some("node1".to_owned())));
+    assert_eq!(store.compare_and_swap("lease", Some("node2".to_owned()), Some("node3".to_owned()))?,
+               CasOutcome::Mismatch(Some("node1".to_owned())));
+    assert_eq!(store.compare_and_swap("lease", Some("node1".to_owned()), Some("node2".to_owned()))?, CasOutcome::Swapped);
+    assert_eq!(store.get("lease")?, Some("node2".to_owned()));
+
+    assert_eq!(store.remove_if_equals("lease", "node1".to_owned())?, CasOutcome::Mismatch(Some("node2".to_owned())));
+    assert_eq!(store.remove_if_equals("lease", "node2".to_owned())?, CasOutcome::Swapped);
+    assert_eq!(store.get("lease")?, None);
+    assert_eq!(store.remove_if_equals("lease", "node2".to_owned())?, CasOutcome::Mismatch(None));
+    assert_eq!(store.compare_and_swap("lease", None, None)?, CasOutcome::Swapped);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("lease")?, None);
+    Ok(())
+}
+
+// Conditional writes from many threads: one lease holder, and a counter that loses no increment.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8).map(|node| {
+        let store = store.clone();
+        thread::spawn(move || {
+            let leader = store.set_if_absent("leader", format!("node{}", node)).unwrap().is_swapped();
+            for _ in 0..25 {
+                let mut current = store.get("counter").unwrap();
+                loop {
+                    let next = (current.as_ref().unwrap().parse::<u64>().unwrap() + 1).to_string();
+                    match store.compare_and_swap("counter", current, Some(next)).unwrap() {
+                        CasOutcome::Swapped => break,
+                        CasOutcome::Mismatch(value) => current = value,
+                    }
+                }
+            }
+            leader
+        })
+    }).collect();
+    let leaders = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|leader| *leader).count();
+
+    assert_eq!(leaders, 1);
+    assert_eq!(store.get("counter")?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_cas() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "lease", "--new", "node1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "lease", "--new", "node2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("node1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "lease", "--expected", "node1", "--new", "node2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "lease", "--expected", "node2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "lease", "--expected", "node2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+}
+
+// A log written while the clock was ahead holds sequencers from the future. Once the clock
+// stepped back, a fresh process must still be able to write over them.
+#[test]
+fn cli_writes_after_clock_step_back() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let ahead = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(3600);
+    let sequencer: Sequencer = serde_json::from_str(&format!("{{\"timestamp\":{}}}", ahead.as_nanos())).unwrap();
+    let storage = Storage::new(temp_dir.path())?;
+    storage.mutate(kvs::Command::Set {key: "key1".into(), value: "ahead".into(), sequencer, expires_at: None})?;
+    drop(storage);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value1", "--new", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+    Ok(())
+}
+
+// Expired keys read as absent everywhere, and their deadline survives reopening.
+#[test]
+fn keys_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("session:1", "alice", Duration::from_millis(200))?;
+    store.set_with_ttl("session:2", "bob", Duration::from_secs(3600))?;
+    store.set("session:3".to_owned(), "carol".to_owned())?;
+    store.expire("session:3", Duration::from_millis(200))?;
+    assert!(matches!(store.expire("session:4", Duration::from_secs(1)), Err(KvError::KeyNotFound)));
+    // a deadline past what the log can hold
+    assert!(matches!(store.set_with_ttl("session:4", "dave", Duration::MAX), Err(KvError::InvalidArgument)));
+    assert!(matches!(store.expire("session:2", Duration::from_secs(u64::MAX)), Err(KvError::InvalidArgument)));
+    assert!(matches!(store.get("session:4"), Ok(None)));
+
+    assert_eq!(store.get("session:1")?, Some("alice".to_owned()));
+    assert!(store.ttl("session:1")?.unwrap() <= Duration::from_millis(200));
+    assert_eq!(store.get("session:3")?, Some("carol".to_owned()));
+    thread::sleep(Duration::from_millis(250));
+
+    assert_eq!(store.get("session:1")?, None);
+    assert_eq!(store.get("session:3")?, None);
+    assert!(matches!(store.ttl("session:1"), Err(KvError::KeyNotFound)));
+    assert!(matches!(store.remove("session:1"), Err(KvError::KeyNotFound)));
+    assert_eq!(store.keys_with_prefix("session:").collect::<Result<Vec<_>>>()?, ["session:2"]);
+    assert_eq!(store.scan(..).rev().count(), 1);
+    assert!(store.set_if_absent("session:1", "dave".to_owned())?.is_swapped());
+    assert_eq!(store.ttl("session:1")?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session:1")?, Some("dave".to_owned()));
+    assert_eq!(store.get("session:3")?, None);
+    let ttl = store.ttl("session:2")?.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    Ok(())
+}
+
+// Compaction drops expired entries, leaving a tombstone where an older value of the key is kept.
+#[test]
+fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, cold_files) = cold_and_hot_files(temp_dir.path())?;
+    // no compaction while writing, the entries must still be there once they expired
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone().compaction_stale_ratio(1.0))?;
+    store.set_with_ttl("cold1", "short", Duration::from_millis(100))?;
+    store.set_with_ttl("session", "short", Duration::from_millis(100))?;
+    store.set_with_ttl("long", "lived", Duration::from_secs(3600))?;
+    for iter in 0..20 {
+        store.set("hot".to_owned(), format!("{}-{}", iter, "y".repeat(100)))?;
+    }
+    drop(store);
+    thread::sleep(Duration::from_millis(150));
+
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    assert!(index.read().unwrap().get_index("session").is_some());
+    storage.compaction(&index)?;
+    assert!(index.read().unwrap().get_index("session").is_none());
+    assert!(index.read().unwrap().get_index("cold1").is_none());
+    drop(storage);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert!(store.file_stats().contains_key(&cold_files[1]));
+    assert_eq!(store.get("cold1")?, None);
+    assert_eq!(store.get("session")?, None);
+    assert_eq!(store.get("long")?, Some("lived".to_owned()));
+    assert_eq!(store.get("cold2")?, Some("x".repeat(100)));
+    drop(store);
+
+    let options = options.compaction_file_count(1);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    assert_eq!(index.read().unwrap().total_stats().stale_bytes, 0);
+    drop(storage);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("cold1")?, None);
+    assert!(store.ttl("long")?.is_some());
+    Ok(())
+}
+
+// Files holding nothing but expired keys are compacted away in the background, even if
+// those keys are never written again.
+#[test]
+fn expired_files_reclaimed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().max_file_size(1024))?;
+    for key_id in 0..550 {
+        store.set_with_ttl(format!("key{}", key_id), "value", Duration::from_millis(1))?;
+    }
+    assert!(data_file_count(&data_dir) > 10);
+
+    for _ in 0..100 {
+        thread::sleep(Duration::from_millis(100));
+        if data_file_count(&data_dir) <= 2 {
+            break;
+        }
+    }
+    assert!(data_file_count(&data_dir) <= 2);
+    assert_eq!(store.file_stats().values().map(|stats| stats.live_bytes).sum::<u64>(), 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.scan(..).count(), 0);
+    Ok(())
+}
+
+// The background sweep takes out the entries whose deadline passed, also after reopening
+// from a checkpoint, and leaves alone keys written again without one.
+#[test]
+fn sweep_takes_out_due_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("kept", "old", Duration::from_millis(1))?;
+    store.set("kept".to_owned(), "new".to_owned())?;
+    store.set_with_ttl("gone", "value", Duration::from_millis(1))?;
+    store.checkpoint()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let live = |store: &KvStore| store.file_stats().values().map(|stats| stats.live_bytes).sum::<u64>();
+    let live_before = live(&store);
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        if live(&store) < live_before {
+            break;
+        }
+    }
+    assert!(live(&store) < live_before);
+    assert!(live(&store) > 0);
+    assert_eq!(store.get("kept")?, Some("new".to_owned()));
+    assert_eq!(store.get("gone")?, None);
+    Ok(())
+}
+
+// An expired key taken out of the index still hides its older values once compaction drops
+// its record, also when the index was checkpointed in between.
+#[test]
+fn evicted_keys_stay_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // no compaction while writing
+    let options = KvStoreOptions::default()
+        .max_file_size(1024)
+        .compaction_file_count(1000)
+        .compaction_stale_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    for key_id in 0..20 {
+        store.set(format!("cold{}", key_id), "x".repeat(100))?;
+    }
+    // in the middle of a file holding only expiring keys
+    for key_id in 0..100 {
+        store.set_with_ttl(format!("ttl{}", key_id), "value", Duration::from_millis(1))?;
+        if key_id == 50 {
+            store.set_with_ttl("key0", "new", Duration::from_millis(1))?;
+        }
+    }
+    // wait for the sweep to count the expired keys as stale
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        if store.file_stats().values().any(|stats| stats.live_bytes == 0) {
+            break;
+        }
+    }
+    assert!(store.file_stats().values().any(|stats| stats.live_bytes == 0));
+    store.checkpoint()?;
+    drop(store);
+
+    let options = options.compaction_stale_ratio(0.5);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    let files_before = index.read().unwrap().file_stats().len();
+    storage.compaction(&index)?;
+    assert!(index.read().unwrap().file_stats().len() < files_before);
+    drop(storage);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("cold3")?, Some("x".repeat(100)));
+    Ok(())
+}
+
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "session", "alice", "--ttl", "1h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("3600s").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "user", "alice"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "user"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("No expiry").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "brief", "x", "--ttl", "100ms"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(150));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "brief"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "brief"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value", "--ttl", "99999999999999999h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("panicked").not());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value", "--ttl", "18446744073709551615s"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("panicked").not());
+}
+
+// Retained versions survive reopening, checkpoints and compaction.
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().retain_versions(4);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key".to_owned(), "1".to_owned())?;
+    let at1 = Sequencer::new()?;
+    store.set("key".to_owned(), "2".to_owned())?;
+    let at2 = Sequencer::new()?;
+    store.remove("key".to_owned())?;
+    let at3 = Sequencer::new()?;
+    store.set("key".to_owned(), "3".to_owned())?;
+    store.set("key".to_owned(), "4".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let history = store.history("key")?;
+        let values: Vec<Option<&str>> = history.iter().map(|(_, value)| value.as_deref()).collect();
+        assert_eq!(values, [Some("4"), Some("3"), None, Some("2")]);
+        assert_eq!(store.get_at("key", &history[1].0)?, Some("3".to_owned()));
+        assert_eq!(store.get_at("key", &Sequencer::new()?)?, Some("4".to_owned()));
+        assert_eq!(store.get_at("key", &at3)?, None);
+        assert_eq!(store.get_at("key", &at2)?, Some("2".to_owned()));
+        // the first version is no longer retained
+        assert_eq!(store.get_at("key", &at1)?, None);
+        assert_eq!(store.history("other")?.len(), 1);
+        assert!(store.history("missing")?.is_empty());
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    store.checkpoint()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    drop(store);
+
+    let options = options.compaction_file_count(1);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    storage.compaction(&index)?;
+    drop(storage);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    drop(store);
+
+    // without retention only the current version is left
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key")?.len(), 1);
+    assert_eq!(store.get("key")?, Some("4".to_owned()));
+    store.set("key".to_owned(), "5".to_owned())?;
+    assert_eq!(store.history("key")?.len(), 1);
+    Ok(())
+}
+
+// Reopening with a shorter retention drops the versions it no longer keeps, also those
+// loaded from a checkpoint.
+#[test]
+fn lowered_retention_prunes_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().retain_versions(5))?;
+    store.set("key".to_owned(), "0".to_owned())?;
+    let at0 = Sequencer::new()?;
+    for value in 1..5 {
+        store.set("key".to_owned(), value.to_string())?;
+    }
+    assert_eq!(store.history("key")?.len(), 5);
+    store.checkpoint()?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().retain_versions(2))?;
+    let values: Vec<Option<String>> = store.history("key")?.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, [Some("4".to_owned()), Some("3".to_owned())]);
+    assert_eq!(store.get_at("key", &at0)?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key")?.len(), 1);
+    assert_eq!(store.get("key")?, Some("4".to_owned()));
+    Ok(())
+}
+
+// Versions of binary keys and values read back through the byte variants.
+#[test]
+fn binary_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().retain_versions(3))?;
+    let key = [0xff, 0x00];
+    store.set_bytes(key, [0xfe, 0x01])?;
+    let at1 = Sequencer::new()?;
+    store.set_bytes(key, [0xfd])?;
+    store.remove_bytes(key)?;
+
+    let history = store.history_bytes(key)?;
+    let values: Vec<Option<Vec<u8>>> = history.iter().map(|(_, value)| value.clone()).collect();
+    assert_eq!(values, [None, Some(vec![0xfd]), Some(vec![0xfe, 0x01])]);
+    assert_eq!(store.get_at_bytes(key, &at1)?, Some(vec![0xfe, 0x01]));
+    assert_eq!(store.get_at_bytes(key, &history[1].0)?, Some(vec![0xfd]));
+    assert_eq!(store.get_at_bytes(key, &Sequencer::new()?)?, None);
+
+    store.set_bytes("text", [0xff])?;
+    assert!(matches!(store.history("text"), Err(KvError::Utf8(_))));
+    assert!(matches!(store.get_at("text", &Sequencer::new()?), Err(KvError::Utf8(_))));
+    Ok(())
+}
+
+// Versions superseded within the time bound are kept however many there are.
+#[test]
+fn version_history_time_bound() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().retain_for(Duration::from_millis(300));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for value in 0..3 {
+        store.set("key".to_owned(), value.to_string())?;
+    }
+    assert_eq!(store.history("key")?.len(), 3);
+    thread::sleep(Duration::from_millis(350));
+    store.set("key".to_owned(), "3".to_owned())?;
+
+    let values: Vec<Option<String>> = store.history("key")?.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, [Some("3".to_owned()), Some("2".to_owned())]);
+    Ok(())
+}
+
+// Compaction keeps retained versions, also when it carries them past a kept file holding a
+// later version of their key.
+#[test]
+fn compaction_keeps_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_file_size(1024)
+        .compaction_file_count(1000)
+        .compaction_stale_ratio(1.0)
+        .retain_versions(3);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key".to_owned(), "1".to_owned())?;
+    for iter in 0..20 {
+        store.set("hot".to_owned(), format!("{}-{}", iter, "y".repeat(100)))?;
+    }
+    store.set("key".to_owned(), "2".to_owned())?;
+    for key_id in 0..20 {
+        store.set(format!("cold{}", key_id), "x".repeat(100))?;
+    }
+    store.set("key".to_owned(), "3".to_owned())?;
+    for iter in 20..40 {
+        store.set("hot".to_owned(), format!("{}-{}", iter, "y".repeat(100)))?;
+    }
+    drop(store);
+
+    let options = options.compaction_stale_ratio(0.5);
+    let storage = Storage::with_options(temp_dir.path(), &options)?;
+    let index = RwLock::new(Index::new());
+    storage.build_index(&mut index.write().unwrap())?;
+    let files_before = index.read().unwrap().file_stats().len();
+    storage.compaction(&index)?;
+    // the cold files are left alone
+    assert!(index.read().unwrap().file_stats().len() > 2);
+    assert!(index.read().unwrap().file_stats().len() < files_before);
+    drop(storage);
+
+    let history = |store: &KvStore| -> Result<Vec<Option<String>>> {
+        Ok(store.history("key")?.into_iter().map(|(_, value)| value).collect())
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(history(&store)?, [Some("3".to_owned()), Some("2".to_owned()), Some("1".to_owned())]);
+    assert_eq!(store.get("hot")?, Some(format!("39-{}", "y".repeat(100))));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(history(&store)?, [Some("3".to_owned())]);
+    assert_eq!(store.get("cold7")?, Some("x".repeat(100)));
+    Ok(())
+}
+
+// Writes a log file the way the very first version did, json commands back to back.
+fn baseline_log(dir: &Path) -> Result<std::path::PathBuf> {
+    let data_dir = dir.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let mut log = Vec::new();
+    for cmd in [
+        kvs::Command::Set {key: "key1".into(), value: "value1".into(), sequencer: kvs::Sequencer::new()?, expires_at: None},
+        kvs::Command::Set {key: "key2".into(), value: "value2".into(), sequencer: kvs::Sequencer::new()?, expires_at: None},
+        kvs::Command::Rm {key: "key1".into(), sequencer: kvs::Sequencer::new()?},
+    ] {
+        log.extend(legacy_json(&cmd));
+    }
+    let path = data_dir.join("00000001.dat");
+    std::fs::write(&path, &log).unwrap();
+    Ok(path)
+}
+
+// A store from the first version is refused untouched until it is upgraded.
+#[test]
+fn upgrade_old_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = baseline_log(temp_dir.path())?;
+    let framed = kvs::Command::Set {key: "key3".into(), value: "value3".into(), sequencer: kvs::Sequencer::new()?, expires_at: None};
+    std::fs::write(temp_dir.path().join("data").join("00000002.dat"), legacy_json_record(&framed)).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::UpgradeRequired(file)) => assert_eq!(file, path),
+        Err(e) => panic!("expected upgrade required, got {:?}", e),
+        Ok(_) => panic!("expected upgrade required"),
+    }
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    assert_eq!(KvStore::upgrade(temp_dir.path(), KvStoreOptions::default())?, 2);
+    assert_eq!(KvStore::upgrade(temp_dir.path(), KvStoreOptions::default())?, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Files stamped with a newer format version are refused, not misread.
+#[test]
+fn newer_format_refused() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    std::fs::create_dir(&data_dir).unwrap();
+    let mut log = b"\x89KVS".to_vec();
+    log.extend_from_slice(&99u32.to_le_bytes());
+    log.extend_from_slice(b"some future record");
+    std::fs::write(data_dir.join("00000001.dat"), &log).unwrap();
+
+    for result in [
+        KvStore::open(temp_dir.path()).map(|_| ()),
+        KvStore::upgrade(temp_dir.path(), KvStoreOptions::default()).map(|_| ()),
+    ] {
+        match result {
+            Err(KvError::UnsupportedVersion {version, ..}) => assert_eq!(version, 99),
+            other => panic!("expected unsupported version, got {:?}", other),
+        }
+    }
+    assert_eq!(std::fs::read(data_dir.join("00000001.dat")).unwrap(), log);
+}
+
+// `kvs upgrade` rewrites an old store, other commands point at it until then.
+#[test]
+fn cli_upgrade() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    baseline_log(temp_dir.path())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs upgrade"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Upgraded 1 log files").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+    Ok(())
+}